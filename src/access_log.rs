@@ -0,0 +1,310 @@
+use std::{
+    io::{self, Write},
+    net::SocketAddr,
+    path::PathBuf,
+    time::Duration,
+};
+
+use log::warn;
+use serde::Serialize;
+use time::OffsetDateTime;
+
+use crate::{appender::Appender, response::MAX_HEADER};
+
+/// How access log lines are written.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum LogFormat {
+    /// The Common Log Format, with the url as the request, the client
+    /// certificate's fingerprint as the user, and the SNI host and
+    /// milliseconds taken added on the end.
+    #[default]
+    Common,
+    /// A JSON object per line.
+    Json,
+}
+
+/// Where access log lines go.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LogTarget {
+    Stdout,
+    /// A file, appended to.
+    File(PathBuf),
+}
+
+/// A response we sent, and who to.
+#[derive(Debug, Clone)]
+pub struct Entry {
+    /// When the connection was accepted.
+    pub timestamp: OffsetDateTime,
+    pub peer: Option<SocketAddr>,
+    /// The host the client asked for in the handshake.
+    pub sni: Option<String>,
+    /// The request line, without its CRLF, if there was one.
+    pub url: Option<String>,
+    pub status: Option<u8>,
+    /// Everything sent, header included.
+    pub bytes: u64,
+    pub duration: Duration,
+    /// The client certificate's fingerprint, in hex.
+    pub fingerprint: Option<String>,
+}
+
+#[derive(Serialize)]
+struct JsonEntry<'a> {
+    time: String,
+    peer: Option<SocketAddr>,
+    sni: Option<&'a str>,
+    url: Option<&'a str>,
+    status: Option<u8>,
+    bytes: u64,
+    duration_ms: u128,
+    fingerprint: Option<&'a str>,
+}
+
+impl Entry {
+    /// Where the request came from, with IPv4 addresses as themselves
+    /// rather than mapped into IPv6.
+    fn peer(&self) -> Option<SocketAddr> {
+        self.peer
+            .map(|peer| SocketAddr::new(peer.ip().to_canonical(), peer.port()))
+    }
+
+    fn common(&self) -> String {
+        let t = self.timestamp;
+        let month = t.month().to_string();
+        let peer = self
+            .peer()
+            .map_or_else(|| "-".into(), |peer| peer.ip().to_string());
+        let status = self
+            .status
+            .map_or_else(|| "-".into(), |status| status.to_string());
+        // Debug quotes and escapes whatever a client sent.
+        let url = self.url.as_deref().unwrap_or("-");
+        let sni = self.sni.as_deref().unwrap_or("-");
+        format!(
+            "{peer} - {} [{:02}/{}/{}:{:02}:{:02}:{:02} +0000] {url:?} {status} {} {sni:?} {}",
+            self.fingerprint.as_deref().unwrap_or("-"),
+            t.day(),
+            &month[..3],
+            t.year(),
+            t.hour(),
+            t.minute(),
+            t.second(),
+            self.bytes,
+            self.duration.as_millis(),
+        )
+    }
+
+    fn json(&self) -> String {
+        let entry = JsonEntry {
+            time: rfc3339(self.timestamp),
+            peer: self.peer(),
+            sni: self.sni.as_deref(),
+            url: self.url.as_deref(),
+            status: self.status,
+            bytes: self.bytes,
+            duration_ms: self.duration.as_millis(),
+            fingerprint: self.fingerprint.as_deref(),
+        };
+        serde_json::to_string(&entry).expect("serializable")
+    }
+}
+
+/// `t` as, say, `2024-03-09T14:05:07Z`.
+pub(crate) fn rfc3339(t: OffsetDateTime) -> String {
+    let t = t.to_offset(time::UtcOffset::UTC);
+    format!(
+        "{}T{:02}:{:02}:{:02}Z",
+        t.date(),
+        t.hour(),
+        t.minute(),
+        t.second()
+    )
+}
+
+/// A line per response, to a file or stdout.
+#[derive(Debug)]
+pub struct AccessLog {
+    format: LogFormat,
+    out: Appender,
+}
+
+impl AccessLog {
+    pub fn open(target: LogTarget, format: LogFormat) -> io::Result<Self> {
+        let out = match target {
+            LogTarget::Stdout => Appender::stdout(),
+            LogTarget::File(path) => Appender::file(path)?,
+        };
+        Ok(Self { format, out })
+    }
+
+    /// Carry on in a new file, for when logrotate has moved ours away.
+    pub fn reopen(&self) -> io::Result<()> {
+        self.out.reopen()
+    }
+
+    pub fn log(&self, entry: &Entry) {
+        let line = match self.format {
+            LogFormat::Common => entry.common(),
+            LogFormat::Json => entry.json(),
+        };
+        if let Err(e) = self.out.append(&line) {
+            warn!("Failed to write to the access log: {e}");
+        }
+    }
+}
+
+/// Passes writes on, keeping count of the bytes and the header they start
+/// with.
+pub(crate) struct Counting<W> {
+    inner: W,
+    bytes: u64,
+    /// The header, as much of it as has been written.
+    head: Vec<u8>,
+}
+
+impl<W: Write> Counting<W> {
+    pub(crate) fn new(inner: W) -> Self {
+        Self {
+            inner,
+            bytes: 0,
+            head: Vec::new(),
+        }
+    }
+
+    pub(crate) fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    /// The status code, if at least that much was written.
+    pub(crate) fn status(&self) -> Option<u8> {
+        std::str::from_utf8(self.head.get(..2)?).ok()?.parse().ok()
+    }
+
+    /// The status code and meta, if the whole header was written.
+    pub(crate) fn header(&self) -> Option<(u8, String)> {
+        let line = self.head.strip_suffix(b"\r\n")?;
+        let line = String::from_utf8_lossy(line);
+        let (status, meta) = line.split_once(' ').unwrap_or((&line, ""));
+        Some((status.parse().ok()?, meta.into()))
+    }
+}
+
+impl<W: Write> Write for Counting<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        if !self.head.ends_with(b"\n") {
+            let room = MAX_HEADER as usize - self.head.len().min(MAX_HEADER as usize);
+            let buf = &buf[..written.min(room)];
+            let end = buf
+                .iter()
+                .position(|&b| b == b'\n')
+                .map_or(buf.len(), |i| i + 1);
+            self.head.extend_from_slice(&buf[..end]);
+        }
+        self.bytes += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use anyhow::Result;
+    use rstest::rstest;
+    use tempfile::TempDir;
+
+    fn entry() -> Entry {
+        Entry {
+            // 2024-03-09 14:05:07 UTC
+            timestamp: OffsetDateTime::from_unix_timestamp(1709993107).expect("timestamp"),
+            peer: Some("[::ffff:192.0.2.1]:4321".parse().expect("addr")),
+            sni: Some("example.org".into()),
+            url: Some("gemini://example.org/\"quoted\"".into()),
+            status: Some(20),
+            bytes: 1234,
+            duration: Duration::from_millis(56),
+            fingerprint: Some("ab12".into()),
+        }
+    }
+
+    #[rstest]
+    #[case::common(
+        LogFormat::Common,
+        "192.0.2.1 - ab12 [09/Mar/2024:14:05:07 +0000] \"gemini://example.org/\\\"quoted\\\"\" 20 1234 \"example.org\" 56\n"
+    )]
+    #[case::json(
+        LogFormat::Json,
+        "{\"time\":\"2024-03-09T14:05:07Z\",\"peer\":\"192.0.2.1:4321\",\"sni\":\"example.org\",\
+         \"url\":\"gemini://example.org/\\\"quoted\\\"\",\"status\":20,\"bytes\":1234,\"duration_ms\":56,\
+         \"fingerprint\":\"ab12\"}\n"
+    )]
+    fn entries_are_formatted(#[case] format: LogFormat, #[case] expected: &str) -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("access.log");
+        let log = AccessLog::open(LogTarget::File(path.clone()), format)?;
+
+        log.log(&entry());
+
+        assert_eq!(std::fs::read_to_string(path)?, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn missing_details_are_dashes() -> Result<()> {
+        let entry = Entry {
+            peer: None,
+            sni: None,
+            url: None,
+            status: None,
+            fingerprint: None,
+            ..entry()
+        };
+
+        assert_eq!(
+            entry.common(),
+            "- - - [09/Mar/2024:14:05:07 +0000] \"-\" - 1234 \"-\" 56"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn the_file_may_be_reopened_after_rotation() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("access.log");
+        let log = AccessLog::open(LogTarget::File(path.clone()), LogFormat::Common)?;
+        log.log(&entry());
+
+        std::fs::rename(&path, dir.path().join("access.log.1"))?;
+        log.reopen()?;
+        log.log(&entry());
+        log.log(&entry());
+
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("access.log.1"))?
+                .lines()
+                .count(),
+            1
+        );
+        assert_eq!(std::fs::read_to_string(path)?.lines().count(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn writes_are_counted() -> Result<()> {
+        let mut buffer = Vec::new();
+        let mut counting = Counting::new(&mut buffer);
+
+        write!(counting, "5")?;
+        write!(counting, "1 Not found\r\n")?;
+
+        assert_eq!(counting.bytes(), 14);
+        assert_eq!(counting.status(), Some(51));
+        assert_eq!(counting.header(), Some((51, "Not found".into())));
+        Ok(())
+    }
+}
@@ -0,0 +1,94 @@
+use std::{
+    fs::OpenOptions,
+    io::{self, Write},
+    path::PathBuf,
+    sync::Mutex,
+};
+
+/// Appends lines from any number of workers to stdout or a file.
+pub(crate) struct Appender {
+    /// The file we write to, or `None` for stdout.
+    path: Option<PathBuf>,
+    out: Mutex<Box<dyn Write + Send>>,
+}
+
+impl std::fmt::Debug for Appender {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Appender")
+            .field("path", &self.path)
+            .finish()
+    }
+}
+
+fn open(path: Option<&PathBuf>) -> io::Result<Box<dyn Write + Send>> {
+    Ok(match path {
+        Some(path) => Box::new(OpenOptions::new().create(true).append(true).open(path)?),
+        None => Box::new(io::stdout()),
+    })
+}
+
+impl Appender {
+    pub(crate) fn stdout() -> Self {
+        Self {
+            path: None,
+            out: Mutex::new(Box::new(io::stdout())),
+        }
+    }
+
+    pub(crate) fn file(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        Ok(Self {
+            out: Mutex::new(open(Some(&path))?),
+            path: Some(path),
+        })
+    }
+
+    /// Start writing to a new file at our path, once the old one has been
+    /// moved aside.
+    pub(crate) fn reopen(&self) -> io::Result<()> {
+        let out = open(self.path.as_ref())?;
+        *self.out.lock().unwrap_or_else(|e| e.into_inner()) = out;
+        Ok(())
+    }
+
+    /// Write `line` and a newline.
+    pub(crate) fn append(&self, line: &str) -> io::Result<()> {
+        let mut line = line.as_bytes().to_vec();
+        line.push(b'\n');
+        // `write_all` may take several writes, so the lock is what keeps
+        // another worker's line from landing in the middle of ours.
+        let mut out = self.out.lock().unwrap_or_else(|e| e.into_inner());
+        out.write_all(&line)?;
+        out.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use anyhow::Result;
+    use std::{sync::Arc, thread};
+    use tempfile::TempDir;
+
+    #[test]
+    fn lines_from_many_threads_stay_whole() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("out.log");
+        let appender = Arc::new(Appender::file(&path)?);
+        let line = "x".repeat(64 * 1024);
+
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let (appender, line) = (appender.clone(), line.clone());
+                thread::spawn(move || appender.append(&line))
+            })
+            .collect();
+        for thread in threads {
+            thread.join().expect("appending thread")?;
+        }
+
+        let written = std::fs::read_to_string(&path)?;
+        assert_eq!(written.lines().collect::<Vec<_>>(), [line.as_str(); 4]);
+        Ok(())
+    }
+}
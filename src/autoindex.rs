@@ -0,0 +1,222 @@
+use std::{fmt::Write, fs::Metadata, path::Path, time::SystemTime};
+
+use percent_encoding::{AsciiSet, CONTROLS, utf8_percent_encode};
+use time::OffsetDateTime;
+
+/// Characters which can't appear as is in a link to a file.
+const SEGMENT: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'/')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'[')
+    .add(b'\\')
+    .add(b']')
+    .add(b'^')
+    .add(b'`')
+    .add(b'{')
+    .add(b'|')
+    .add(b'}');
+
+/// How to order a listing.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum SortBy {
+    /// Alphabetically, directories first.
+    #[default]
+    Name,
+    /// Newest first.
+    Modified,
+    /// Largest first.
+    Size,
+}
+
+/// Generated listings for directories without an index.
+#[derive(Debug, Clone, Default)]
+pub struct AutoIndex {
+    /// Show each file's size.
+    pub sizes: bool,
+    /// Show when each entry was last modified.
+    pub modified: bool,
+    pub sort: SortBy,
+}
+
+struct Entry {
+    name: String,
+    metadata: Metadata,
+}
+
+impl Entry {
+    fn modified(&self) -> SystemTime {
+        self.metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH)
+    }
+}
+
+impl AutoIndex {
+    /// A gemtext page of links to the entries in `dir`, which was asked for
+    /// as `url_path`, leaving out any whose names aren't `visible`, so that
+    /// what's listed is what's served.
+    pub fn render(
+        &self,
+        dir: &Path,
+        url_path: &str,
+        visible: impl Fn(&str) -> bool,
+    ) -> std::io::Result<String> {
+        let mut entries = std::fs::read_dir(dir)?
+            .filter_map(Result::ok)
+            .filter_map(|entry| {
+                Some(Entry {
+                    name: entry.file_name().into_string().ok()?,
+                    metadata: entry.metadata().ok()?,
+                })
+            })
+            .filter(|entry| visible(&entry.name))
+            .collect::<Vec<_>>();
+        match self.sort {
+            SortBy::Name => entries.sort_by(|a, b| {
+                (!a.metadata.is_dir(), &a.name).cmp(&(!b.metadata.is_dir(), &b.name))
+            }),
+            SortBy::Modified => entries.sort_by_key(|entry| std::cmp::Reverse(entry.modified())),
+            SortBy::Size => entries.sort_by_key(|entry| std::cmp::Reverse(entry.metadata.len())),
+        }
+
+        let mut page = format!("# Index of {url_path}\n\n");
+        if url_path != "/" {
+            page.push_str("=> ../ ..\n");
+        }
+        for entry in entries {
+            let slash = if entry.metadata.is_dir() { "/" } else { "" };
+            let link = utf8_percent_encode(&entry.name, SEGMENT);
+            // A newline in a name would start a line of gemtext of its own.
+            let label: String = entry
+                .name
+                .chars()
+                .map(|c| if c.is_control() { '\u{fffd}' } else { c })
+                .collect();
+            let _ = write!(page, "=> {link}{slash} {label}{slash}");
+            let mut details = Vec::new();
+            if self.sizes && entry.metadata.is_file() {
+                details.push(human_size(entry.metadata.len()));
+            }
+            if self.modified {
+                let modified = OffsetDateTime::from(entry.modified());
+                details.push(format!(
+                    "{} {:02}:{:02}",
+                    modified.date(),
+                    modified.hour(),
+                    modified.minute()
+                ));
+            }
+            if !details.is_empty() {
+                let _ = write!(page, " ({})", details.join(", "));
+            }
+            page.push('\n');
+        }
+        Ok(page)
+    }
+}
+
+fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{size:.1} {}", UNITS[unit])
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use anyhow::Result;
+    use rstest::rstest;
+    use std::time::Duration;
+    use tempfile::TempDir;
+
+    fn dir() -> Result<TempDir> {
+        let dir = TempDir::new()?;
+        std::fs::create_dir(dir.path().join("posts"))?;
+        std::fs::write(dir.path().join("b.gmi"), "b")?;
+        std::fs::write(dir.path().join("a note.txt"), "a".repeat(2048))?;
+        std::fs::write(dir.path().join(".secret"), "")?;
+        let old = std::fs::File::options()
+            .write(true)
+            .open(dir.path().join("b.gmi"))?;
+        old.set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(86400))?;
+        Ok(dir)
+    }
+
+    #[test]
+    fn entries_are_linked_directories_first() -> Result<()> {
+        let dir = dir()?;
+
+        let page = AutoIndex::default().render(dir.path(), "/files/", |name| name != ".secret")?;
+
+        assert_eq!(
+            page,
+            "# Index of /files/\n\n\
+             => ../ ..\n\
+             => posts/ posts/\n\
+             => a%20note.txt a note.txt\n\
+             => b.gmi b.gmi\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn details_may_be_shown() -> Result<()> {
+        let dir = dir()?;
+        let index = AutoIndex {
+            sizes: true,
+            modified: true,
+            sort: SortBy::Modified,
+        };
+
+        let page = index.render(dir.path(), "/", |name| name != "posts")?;
+
+        assert!(page.contains("=> .secret .secret (0 B, "), "{page}");
+        assert!(
+            page.contains("=> a%20note.txt a note.txt (2.0 KiB, "),
+            "{page}"
+        );
+        assert!(
+            page.ends_with("=> b.gmi b.gmi (1 B, 1970-01-02 00:00)\n"),
+            "{page}"
+        );
+        assert!(!page.contains("=> ../"), "{page}");
+        assert!(!page.contains("posts"), "{page}");
+        Ok(())
+    }
+
+    #[test]
+    fn names_cannot_inject_gemtext() -> Result<()> {
+        let dir = TempDir::new()?;
+        std::fs::write(dir.path().join("x\r\n# Pwned\n=> gemini:evil"), "")?;
+
+        let page = AutoIndex::default().render(dir.path(), "/", |_| true)?;
+
+        assert_eq!(
+            page,
+            "# Index of /\n\n\
+             => x%0D%0A%23%20Pwned%0A=%3E%20gemini:evil x\u{fffd}\u{fffd}# Pwned\u{fffd}=> gemini:evil\n"
+        );
+        Ok(())
+    }
+
+    #[rstest]
+    #[case(0, "0 B")]
+    #[case(1023, "1023 B")]
+    #[case(1536, "1.5 KiB")]
+    #[case(5 * 1024 * 1024, "5.0 MiB")]
+    fn sizes_are_human_readable(#[case] bytes: u64, #[case] expected: &str) {
+        assert_eq!(human_size(bytes), expected);
+    }
+}
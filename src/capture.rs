@@ -0,0 +1,314 @@
+use std::{
+    fmt,
+    io::{self, BufRead},
+    net::SocketAddr,
+    path::PathBuf,
+};
+
+use log::warn;
+use rustls::pki_types::CertificateDer;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    access_log::Counting,
+    appender::Appender,
+    client_cert::ClientCertificate,
+    server::{Error, Service},
+    vhost::{VirtualHost, VirtualHostError, VirtualHosts},
+};
+
+/// A request as it arrived, and the header we answered it with.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Exchange {
+    /// When the connection was accepted, as `2024-03-09T14:05:07Z`.
+    pub time: String,
+    /// Exactly what the client sent, CRLF and all, with anything which
+    /// isn't UTF-8 replaced.
+    pub request: String,
+    /// What the client sent as hex, if it wasn't UTF-8.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub raw: Option<String>,
+    pub peer: Option<SocketAddr>,
+    /// The host the client asked for in the handshake.
+    pub sni: Option<String>,
+    /// The client certificate, DER encoded as hex.
+    pub client_certificate: Option<String>,
+    pub status: Option<u8>,
+    pub meta: Option<String>,
+}
+
+impl Exchange {
+    /// The request line as the client sent it.
+    pub fn request_bytes(&self) -> Vec<u8> {
+        self.raw
+            .as_deref()
+            .and_then(from_hex)
+            .unwrap_or_else(|| self.request.as_bytes().to_vec())
+    }
+}
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Appends every request served to a file, a JSON [`Exchange`] per line,
+/// for [`Replayer`] to try again later.
+#[derive(Debug)]
+pub struct Recorder {
+    out: Appender,
+}
+
+impl Recorder {
+    pub fn open(path: impl Into<PathBuf>) -> io::Result<Self> {
+        Ok(Self {
+            out: Appender::file(path)?,
+        })
+    }
+
+    /// Carry on in a new file, once the capture so far has been moved away.
+    pub fn reopen(&self) -> io::Result<()> {
+        self.out.reopen()
+    }
+
+    pub fn record(&self, exchange: &Exchange) {
+        let line = serde_json::to_string(exchange).expect("serializable");
+        if let Err(e) = self.out.append(&line) {
+            warn!("Failed to write to the capture file: {e}");
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum CaptureError {
+    #[error("Failed to read the capture: {0}")]
+    Read(#[from] io::Error),
+    #[error("Line {line} of the capture is not a request: {source}")]
+    Parse {
+        line: usize,
+        source: serde_json::Error,
+    },
+}
+
+/// A request answered differently on replay.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Difference {
+    /// Where the request is in the capture, counting from 1.
+    pub line: usize,
+    pub request: String,
+    /// The header first sent, or `-` if there wasn't one.
+    pub recorded: String,
+    pub replayed: String,
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "line {}: {:?}", self.line, self.request.trim_end())?;
+        writeln!(f, "  recorded: {}", self.recorded)?;
+        write!(f, "  replayed: {}", self.replayed)
+    }
+}
+
+/// What replaying a capture found.
+#[derive(Debug, Default)]
+pub struct Report {
+    pub replayed: usize,
+    pub differences: Vec<Difference>,
+}
+
+fn header(status: Option<u8>, meta: Option<&str>) -> String {
+    match (status, meta) {
+        (Some(status), Some(meta)) => format!("{status} {meta}"),
+        (Some(status), None) => status.to_string(),
+        (None, _) => "-".into(),
+    }
+}
+
+/// Answers captured requests with the handlers given, without TLS or
+/// sockets, to see whether they're answered as they were before.
+pub struct Replayer {
+    service: Service,
+}
+
+impl Replayer {
+    pub fn new(hosts: Vec<VirtualHost>) -> Result<Self, VirtualHostError> {
+        let mut virtual_hosts = VirtualHosts::default();
+        for host in hosts {
+            virtual_hosts.add(host)?;
+        }
+        Ok(Self {
+            service: Service::offline(virtual_hosts),
+        })
+    }
+
+    /// The status and meta `exchange`'s request gets now.
+    pub fn replay(&self, exchange: &Exchange) -> (Option<u8>, Option<String>) {
+        let client_certificate = exchange
+            .client_certificate
+            .as_deref()
+            .and_then(from_hex)
+            .map(|der| ClientCertificate::new(CertificateDer::from(der)));
+        let raw = String::try_from(exchange.request_bytes()).map_err(Error::from);
+        let Some(resp) = self.service.respond(raw, client_certificate, exchange.peer) else {
+            return (None, None);
+        };
+        let mut out = Counting::new(io::sink());
+        if let Err(e) = resp.send(&mut out) {
+            warn!("Failed to replay {:?}: {e}", exchange.request.trim_end());
+        }
+        match out.header() {
+            Some((status, meta)) => (Some(status), Some(meta)),
+            None => (out.status(), None),
+        }
+    }
+
+    /// Replay each [`Exchange`] read from `capture`, one per line.
+    pub fn replay_all(&self, capture: impl BufRead) -> Result<Report, CaptureError> {
+        let mut report = Report::default();
+        for (i, line) in capture.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let exchange: Exchange =
+                serde_json::from_str(&line).map_err(|source| CaptureError::Parse {
+                    line: i + 1,
+                    source,
+                })?;
+            let (status, meta) = self.replay(&exchange);
+            report.replayed += 1;
+            if (status, &meta) != (exchange.status, &exchange.meta) {
+                report.differences.push(Difference {
+                    line: i + 1,
+                    request: exchange.request,
+                    recorded: header(exchange.status, exchange.meta.as_deref()),
+                    replayed: header(status, meta.as_deref()),
+                });
+            }
+        }
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        handler::Handler,
+        request::Request,
+        response::{ErrResponse, Response, SuccessResponse},
+        status::{CertificateRequired, Status, Success},
+        testing::self_signed,
+    };
+    use anyhow::Result;
+    use tempfile::TempDir;
+
+    /// Answers with the request's path, if the client has a certificate.
+    struct Members;
+
+    impl Handler for Members {
+        fn handle_request(&self, request: &Request) -> Option<Response> {
+            Some(match request.client_certificate() {
+                Some(_) => Response::Fixed(SuccessResponse {
+                    status: Success::Generic,
+                    mime: format!("text/plain; path={}", request.url().path()).into(),
+                    body: "".into(),
+                }),
+                None => Response::Err(ErrResponse::from_status(Status::CertificateRequired(
+                    CertificateRequired::Generic,
+                ))),
+            })
+        }
+    }
+
+    fn replayer() -> Result<Replayer> {
+        let mut host = VirtualHost::without_certificate(["example.org"]);
+        host.add_handler(Box::new(Members));
+        Ok(Replayer::new(vec![host])?)
+    }
+
+    fn exchange(request: &str, status: u8, meta: &str) -> Exchange {
+        let (cert, _) = self_signed(&["me"]);
+        Exchange {
+            time: "2024-03-09T14:05:07Z".into(),
+            request: format!("{request}\r\n"),
+            raw: None,
+            peer: Some("192.0.2.1:4321".parse().expect("addr")),
+            sni: Some("example.org".into()),
+            client_certificate: Some(to_hex(&cert)),
+            status: Some(status),
+            meta: Some(meta.into()),
+        }
+    }
+
+    #[test]
+    fn recorded_requests_are_replayed() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("capture.jsonl");
+        let recorder = Recorder::open(&path)?;
+        let anonymous = Exchange {
+            client_certificate: None,
+            ..exchange("gemini://example.org/a", 60, "CertificateRequired(Generic)")
+        };
+        recorder.record(&exchange(
+            "gemini://example.org/a",
+            20,
+            "text/plain; path=/a",
+        ));
+        recorder.record(&anonymous);
+
+        let report = replayer()?.replay_all(io::BufReader::new(std::fs::File::open(path)?))?;
+
+        assert_eq!(report.replayed, 2);
+        assert_eq!(report.differences, []);
+        Ok(())
+    }
+
+    #[test]
+    fn differences_are_reported() -> Result<()> {
+        let capture = [
+            exchange("gemini://example.org/a", 20, "text/plain; path=/a"),
+            exchange("gemini://example.org/b", 20, "text/plain; path=/a"),
+            exchange("gemini://elsewhere.org/", 20, "text/plain; path=/"),
+        ]
+        .iter()
+        .map(|exchange| serde_json::to_string(exchange).expect("serializable") + "\n")
+        .collect::<String>();
+
+        let report = replayer()?.replay_all(capture.as_bytes())?;
+
+        assert_eq!(report.replayed, 3);
+        assert_eq!(
+            report.differences,
+            [
+                Difference {
+                    line: 2,
+                    request: "gemini://example.org/b\r\n".into(),
+                    recorded: "20 text/plain; path=/a".into(),
+                    replayed: "20 text/plain; path=/b".into(),
+                },
+                Difference {
+                    line: 3,
+                    request: "gemini://elsewhere.org/\r\n".into(),
+                    recorded: "20 text/plain; path=/".into(),
+                    replayed: "53 PermanentFailure(ProxyRequestRefused)".into(),
+                },
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn malformed_lines_are_refused() -> Result<()> {
+        let result = replayer()?.replay_all("\n{\"request\": 1}\n".as_bytes());
+
+        assert!(matches!(result, Err(CaptureError::Parse { line: 2, .. })));
+        Ok(())
+    }
+}
@@ -0,0 +1,386 @@
+use std::{
+    io::{BufRead, BufReader, Cursor, Read},
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    time::{Duration, Instant},
+};
+
+use log::{debug, warn};
+
+use crate::{
+    handler::{Handler, Prefix},
+    request::Request,
+    response::{ErrResponse, MAX_HEADER, RawResponse, Response},
+    status::{PermanentFailure, Status, StatusCode, TemporaryFailure},
+};
+
+/// How long a script may run unless told otherwise.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often we check whether a script has finished.
+const WATCH_POLL: Duration = Duration::from_millis(10);
+
+/// Runs executables under a prefix, following the usual Gemini CGI
+/// conventions.
+///
+/// A script's stdout is sent to the client as is, so it must begin with a
+/// Gemini status line. The first existing file along the request's path is
+/// the script, and whatever follows becomes `PATH_INFO`.
+#[derive(Debug)]
+pub struct CgiHandler {
+    /// The directory containing the scripts.
+    path: PathBuf,
+    /// The prefix required in the url for this handler to match.
+    prefix: Prefix,
+    /// How long a script may run, including sending its output.
+    timeout: Duration,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum CgiHandlerError {
+    #[error("Path is relative")]
+    RelativePath,
+    #[error("Path does not exist")]
+    MissingPath,
+}
+
+/// Why a CGI script or similar backend gave us nothing to send.
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum GatewayError {
+    #[error("Failed to start: {0}")]
+    Spawn(std::io::Error),
+    #[error("Failed to connect: {0}")]
+    Connect(std::io::Error),
+    #[error("Failed to read output: {0}")]
+    Read(std::io::Error),
+    #[error("Exited without output")]
+    NoHeader,
+    #[error("Invalid status line `{0}`")]
+    InvalidHeader(String),
+}
+
+/// A script found along a request's path.
+#[derive(Debug, PartialEq)]
+struct Script {
+    path: PathBuf,
+    /// The part of the url path naming the script.
+    name: String,
+    /// The rest of the url path.
+    path_info: String,
+}
+
+impl CgiHandler {
+    pub fn new(
+        path: impl Into<PathBuf>,
+        prefix: impl Into<String>,
+    ) -> Result<Self, CgiHandlerError> {
+        let path: PathBuf = path.into();
+        if !path.is_absolute() {
+            Err(CgiHandlerError::RelativePath)
+        } else if !path.exists() {
+            Err(CgiHandlerError::MissingPath)
+        } else {
+            Ok(Self {
+                path,
+                prefix: Prefix::from(prefix.into()),
+                timeout: DEFAULT_TIMEOUT,
+            })
+        }
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    fn find_script(&self, url_path: &str) -> Option<Script> {
+        let rest = url_path.strip_prefix(&*self.prefix)?;
+        let mut path = self.path.clone();
+        let mut end = self.prefix.len();
+        for segment in rest.split('/') {
+            // Never run hidden files, nor anything outside our directory.
+            if segment.is_empty() || segment.starts_with('.') {
+                return None;
+            }
+            path.push(segment);
+            end += segment.len();
+            if path.is_file() {
+                return Some(Script {
+                    path,
+                    name: url_path[..end].into(),
+                    path_info: url_path[end..].into(),
+                });
+            } else if !path.is_dir() {
+                return None;
+            }
+            end += 1;
+        }
+        None
+    }
+
+    fn command(&self, script: &Script, request: &Request) -> Command {
+        let mut command = Command::new(&script.path);
+        command
+            .env_clear()
+            .envs(std::env::var_os("PATH").map(|path| ("PATH", path)))
+            .envs(variables(request, &script.name, &script.path_info))
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit());
+        if let Some(dir) = script.path.parent() {
+            command.current_dir(dir);
+        }
+        command
+    }
+
+    /// Start the script and wait for its status line.
+    fn run(&self, script: &Script, request: &Request) -> Result<Response, GatewayError> {
+        let mut child = self
+            .command(script, request)
+            .spawn()
+            .map_err(GatewayError::Spawn)?;
+        let mut stdout = BufReader::new(child.stdout.take().expect("stdout is piped"));
+        let (path, timeout) = (script.path.clone(), self.timeout);
+        std::thread::spawn(move || watch(child, &path, timeout));
+
+        let header = read_header(&mut stdout)?;
+        Ok(Response::Raw(RawResponse {
+            body: Box::new(Cursor::new(header).chain(stdout)),
+        }))
+    }
+}
+
+/// The variables describing `request` to a gateway program, which was found
+/// at `script_name` and should handle `path_info`.
+pub(crate) fn variables(
+    request: &Request,
+    script_name: &str,
+    path_info: &str,
+) -> Vec<(&'static str, String)> {
+    let url = request.url();
+    let mut variables = vec![
+        ("GATEWAY_INTERFACE", "CGI/1.1".into()),
+        ("SERVER_PROTOCOL", "GEMINI".into()),
+        (
+            "SERVER_SOFTWARE",
+            concat!("inimeg/", env!("CARGO_PKG_VERSION")).into(),
+        ),
+        ("SERVER_NAME", url.host_str().unwrap_or_default().into()),
+        ("GEMINI_URL", url.as_str().into()),
+        ("SCRIPT_NAME", script_name.into()),
+        ("PATH_INFO", path_info.into()),
+        ("QUERY_STRING", url.query().unwrap_or_default().into()),
+    ];
+    if let Some(addr) = request.peer_addr() {
+        variables.push(("REMOTE_ADDR", addr.ip().to_canonical().to_string()));
+    }
+    if let Some(certificate) = request.client_certificate() {
+        variables.push(("AUTH_TYPE", "CERTIFICATE".into()));
+        variables.push(("TLS_CLIENT_HASH", certificate.fingerprint_hex()));
+    }
+    variables
+}
+
+/// Read a gateway's status line, checking that it is one.
+pub(crate) fn read_header(reader: &mut impl BufRead) -> Result<Vec<u8>, GatewayError> {
+    let mut header = Vec::new();
+    reader
+        .take(MAX_HEADER)
+        .read_until(b'\n', &mut header)
+        .map_err(GatewayError::Read)?;
+    if header.is_empty() {
+        Err(GatewayError::NoHeader)
+    } else if !is_valid_header(&header) {
+        Err(GatewayError::InvalidHeader(
+            String::from_utf8_lossy(&header).into_owned(),
+        ))
+    } else {
+        Ok(header)
+    }
+}
+
+/// Reap `child` when it exits, or kill it once `timeout` has passed.
+///
+/// Killing the script closes its stdout, which ends the response.
+fn watch(mut child: Child, path: &Path, timeout: Duration) {
+    let deadline = Instant::now() + timeout;
+    loop {
+        match child.try_wait() {
+            Ok(Some(status)) if !status.success() => {
+                return warn!("CGI script {path:?} exited with {status}");
+            }
+            Ok(Some(_)) => return,
+            Ok(None) if Instant::now() >= deadline => {
+                warn!("CGI script {path:?} timed out, killing it");
+                let _ = child.kill();
+                let _ = child.wait();
+                return;
+            }
+            Ok(None) => std::thread::sleep(WATCH_POLL),
+            Err(e) => return warn!("Failed to wait for CGI script {path:?}: {e}"),
+        }
+    }
+}
+
+fn is_valid_header(header: &[u8]) -> bool {
+    match header.strip_suffix(b"\r\n") {
+        Some([tens, units, rest @ ..])
+            if tens.is_ascii_digit()
+                && units.is_ascii_digit()
+                && matches!(rest.first(), None | Some(b' ')) =>
+        {
+            StatusCode::try_from((tens - b'0') * 10 + (units - b'0')).is_ok()
+        }
+        _ => false,
+    }
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    path.metadata()
+        .is_ok_and(|metadata| metadata.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(not(unix))]
+fn is_executable(_path: &Path) -> bool {
+    true
+}
+
+impl Handler for CgiHandler {
+    fn handle_request(&self, request: &Request) -> Option<Response> {
+        let url_path = request.url().path();
+        if !url_path.starts_with(&*self.prefix) {
+            return None;
+        }
+        let Some(script) = self
+            .find_script(url_path)
+            .filter(|script| is_executable(&script.path))
+        else {
+            return Some(Response::Err(ErrResponse::from_status(
+                Status::PermanentFailure(PermanentFailure::NotFound),
+            )));
+        };
+        debug!("Running CGI script {:?}", script.path);
+        Some(self.run(&script, request).unwrap_or_else(|e| {
+            warn!("CGI script {:?} failed: {e}", script.path);
+            Response::Err(ErrResponse::from_status(Status::TemporaryFailure(
+                TemporaryFailure::CGIError,
+            )))
+        }))
+    }
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::*;
+    use crate::{
+        client_cert::ClientCertificate,
+        testing::{get, sent},
+    };
+    use anyhow::Result;
+    use rstest::rstest;
+    use std::os::unix::fs::PermissionsExt;
+    use tempfile::TempDir;
+
+    fn script(dir: &TempDir, name: &str, body: &str) -> Result<()> {
+        let path = dir.path().join(name);
+        std::fs::write(&path, format!("#!/bin/sh\n{body}\n"))?;
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755))?;
+        Ok(())
+    }
+
+    #[test]
+    fn scripts_see_the_request() -> Result<()> {
+        let dir = TempDir::new()?;
+        script(
+            &dir,
+            "env.sh",
+            r#"printf '20 text/plain\r\n'
+for name in GATEWAY_INTERFACE SERVER_NAME SCRIPT_NAME PATH_INFO QUERY_STRING REMOTE_ADDR TLS_CLIENT_HASH; do
+    eval "echo $name=\$$name"
+done"#,
+        )?;
+        let handler = CgiHandler::new(dir.path(), "cgi-bin")?;
+        let certificate = ClientCertificate::new(b"abc".to_vec().into());
+        let request = "gemini://example.com/cgi-bin/env.sh/extra/bits?a=b\r\n"
+            .parse::<Request>()?
+            .with_peer_addr(Some("[::ffff:127.0.0.1]:4321".parse()?))
+            .with_client_certificate(Some(certificate.clone()));
+
+        let output = sent(handler.handle_request(&request).expect("handled"))?;
+
+        assert_eq!(
+            output,
+            format!(
+                "20 text/plain\r\n\
+                 GATEWAY_INTERFACE=CGI/1.1\n\
+                 SERVER_NAME=example.com\n\
+                 SCRIPT_NAME=/cgi-bin/env.sh\n\
+                 PATH_INFO=/extra/bits\n\
+                 QUERY_STRING=a=b\n\
+                 REMOTE_ADDR=127.0.0.1\n\
+                 TLS_CLIENT_HASH={}\n",
+                certificate.fingerprint_hex()
+            )
+        );
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::crash("exit 1")]
+    #[case::bad_status("echo hello")]
+    #[case::unterminated_status("printf '20 text/plain'")]
+    fn failures_are_cgi_errors(#[case] body: &str) -> Result<()> {
+        let dir = TempDir::new()?;
+        script(&dir, "fail.sh", body)?;
+        let handler = CgiHandler::new(dir.path(), "/")?;
+
+        let output = get(&handler, "gemini://example.com/fail.sh")?.expect("handled");
+
+        assert_eq!(output, "42 TemporaryFailure(CGIError)\r\n");
+        Ok(())
+    }
+
+    #[test]
+    fn slow_scripts_are_killed() -> Result<()> {
+        let dir = TempDir::new()?;
+        script(&dir, "slow.sh", "exec sleep 10")?;
+        let handler = CgiHandler::new(dir.path(), "/")?.with_timeout(Duration::from_millis(100));
+
+        let start = Instant::now();
+        let output = get(&handler, "gemini://example.com/slow.sh")?.expect("handled");
+
+        assert_eq!(output, "42 TemporaryFailure(CGIError)\r\n");
+        assert!(start.elapsed() < Duration::from_secs(5));
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::missing("gemini://example.com/cgi-bin/missing.sh")]
+    #[case::not_executable("gemini://example.com/cgi-bin/data.txt")]
+    #[case::hidden("gemini://example.com/cgi-bin/.hidden.sh")]
+    #[case::directory("gemini://example.com/cgi-bin/")]
+    fn only_executables_are_run(#[case] url: &str) -> Result<()> {
+        let dir = TempDir::new()?;
+        script(&dir, ".hidden.sh", "printf '20 text/plain\\r\\n'")?;
+        std::fs::write(dir.path().join("data.txt"), "data")?;
+        let handler = CgiHandler::new(dir.path(), "cgi-bin")?;
+
+        let output = get(&handler, url)?.expect("handled");
+
+        assert_eq!(output, "51 PermanentFailure(NotFound)\r\n");
+        Ok(())
+    }
+
+    #[test]
+    fn ignores_requests_not_starting_with_its_prefix() -> Result<()> {
+        let dir = TempDir::new()?;
+        let handler = CgiHandler::new(dir.path(), "cgi-bin")?;
+
+        let request: Request = "gemini://example.com/static/foo.sh\r\n".parse()?;
+
+        assert!(handler.handle_request(&request).is_none());
+        Ok(())
+    }
+}
@@ -1,17 +1,63 @@
-use std::path::PathBuf;
+use std::{
+    num::{NonZeroU64, NonZeroUsize},
+    path::PathBuf,
+    str::FromStr,
+};
+
+use crate::config::LogFormatConfig;
 
 /// Serve content.
+///
+/// Settings may be given in a config file, command line flags, or both, in
+/// which case flags take precedence. Host flags without `--certificate`
+/// change the config file's host, if it has only one.
 #[derive(clap::Args)]
 pub struct Serve {
-    /// The certificate this server will use to authenticate itself to clients.
+    /// A TOML config file describing hosts, handlers and settings.
     #[arg(long)]
-    pub certificate: PathBuf,
+    pub config: Option<PathBuf>,
+    /// The certificate this server will use to authenticate itself to clients.
+    #[arg(long, required_unless_present_any = ["config", "vhost"], requires = "private_key")]
+    pub certificate: Option<PathBuf>,
     /// The private key for this certificate.
+    #[arg(long, requires = "certificate")]
+    pub private_key: Option<PathBuf>,
+    /// The port the server should listen on [default: 1965].
+    #[arg(long, short)]
+    pub port: Option<usize>,
+    /// Number of connections to handle concurrently [default: 8].
     #[arg(long)]
-    pub private_key: PathBuf,
-    /// The port the server should listen on.
-    #[arg(long, short, default_value_t = 1965)]
-    pub port: usize,
+    pub workers: Option<NonZeroUsize>,
+    /// Number of accepted connections which may wait for a free worker.
+    /// Beyond this, new connections wait in the kernel's backlog [default: 32].
+    #[arg(long)]
+    pub queue_depth: Option<usize>,
+    /// Seconds a client may take to complete the TLS handshake [default: 10].
+    #[arg(long)]
+    pub handshake_timeout: Option<NonZeroU64>,
+    /// Seconds a client may take to send its request [default: 10].
+    #[arg(long)]
+    pub read_timeout: Option<NonZeroU64>,
+    /// Seconds a client may go without accepting any of the response
+    /// [default: 30].
+    #[arg(long)]
+    pub write_timeout: Option<NonZeroU64>,
+    /// Seconds to let connections in flight finish on SIGTERM or SIGINT
+    /// [default: 30].
+    #[arg(long)]
+    pub shutdown_grace: Option<u64>,
+    /// Log every response to this file, or to stdout if `-`. The file is
+    /// reopened on SIGHUP.
+    #[arg(long)]
+    pub access_log: Option<PathBuf>,
+    /// How to write the access log, to stdout unless `--access-log` says
+    /// otherwise [default: common].
+    #[arg(long, value_enum)]
+    pub access_log_format: Option<LogFormatConfig>,
+    /// Record every request to this file, as JSON lines, for `inimeg
+    /// replay`. The file is reopened on SIGHUP.
+    #[arg(long)]
+    pub capture: Option<PathBuf>,
     /// Static dirs to serve.
     ///
     /// The name of every dir will be used to filter incoming requests. For
@@ -26,10 +72,87 @@ pub struct Serve {
     /// query will be routed to this static dir.  (This may well be desirable.)
     #[arg(long)]
     pub root_dir: Option<PathBuf>,
+
+    /// Hostnames served with `--certificate`. If none are given, requests
+    /// for any host not claimed by a `--vhost` are served.
+    #[arg(long)]
+    pub hostname: Vec<String>,
+
+    /// An additional capsule, as `HOSTNAME:CERTIFICATE:PRIVATE_KEY:ROOT_DIR`.
+    ///
+    /// The certificate is chosen by SNI and requests for `HOSTNAME` are
+    /// served statically from `ROOT_DIR`.
+    #[arg(long)]
+    pub vhost: Vec<VirtualHost>,
+
+    /// Generate a self-signed certificate and key at `--certificate` and
+    /// `--private-key` for `--hostname` (or localhost) if neither exists.
+    #[arg(long)]
+    pub auto_cert: bool,
+}
+
+/// Generate a self-signed certificate and key.
+#[derive(clap::Args)]
+pub struct Gencert {
+    /// Where to write the certificate.
+    #[arg(long)]
+    pub certificate: PathBuf,
+    /// Where to write the private key.
+    #[arg(long)]
+    pub private_key: PathBuf,
+    /// Hostnames or IP addresses the certificate is for. The first is used as
+    /// the common name.
+    #[arg(long, required = true)]
+    pub hostname: Vec<String>,
+    /// Days the certificate is valid for.
+    #[arg(long, default_value_t = inimeg::gencert::DEFAULT_DAYS)]
+    pub days: u32,
+    /// Overwrite existing files.
+    #[arg(long)]
+    pub force: bool,
+}
+
+/// Answer requests recorded by `serve --capture` again, without TLS, and
+/// report any whose status or meta differ from before.
+///
+/// Exits with an error if any do.
+#[derive(clap::Args)]
+pub struct Replay {
+    /// The config file describing the hosts and handlers to replay against.
+    #[arg(long)]
+    pub config: PathBuf,
+    /// The recorded requests.
+    pub capture: PathBuf,
+}
+
+/// A virtual host given on the command line.
+#[derive(Debug, Clone)]
+pub struct VirtualHost {
+    pub hostname: String,
+    pub certificate: PathBuf,
+    pub private_key: PathBuf,
+    pub root_dir: PathBuf,
+}
+
+impl FromStr for VirtualHost {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.splitn(4, ':').collect::<Vec<_>>()[..] {
+            [hostname, certificate, private_key, root_dir] if !hostname.is_empty() => Ok(Self {
+                hostname: hostname.into(),
+                certificate: certificate.into(),
+                private_key: private_key.into(),
+                root_dir: root_dir.into(),
+            }),
+            _ => Err("expected HOSTNAME:CERTIFICATE:PRIVATE_KEY:ROOT_DIR".into()),
+        }
+    }
 }
 
 /// Inimeg, a Gemini server built from the ground up.
 #[derive(clap::Parser)]
 pub enum Cli {
-    Serve(Serve),
+    Serve(Box<Serve>),
+    Gencert(Gencert),
+    Replay(Replay),
 }
@@ -0,0 +1,49 @@
+use rustls::pki_types::CertificateDer;
+use sha2::{Digest, Sha256};
+
+/// The certificate a client presented, if it chose to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientCertificate {
+    der: CertificateDer<'static>,
+    fingerprint: [u8; 32],
+}
+
+impl ClientCertificate {
+    pub fn new(der: CertificateDer<'static>) -> Self {
+        let fingerprint = Sha256::digest(&der).into();
+        Self { der, fingerprint }
+    }
+
+    /// The DER-encoded certificate.
+    pub fn der(&self) -> &CertificateDer<'static> {
+        &self.der
+    }
+
+    /// The SHA-256 digest of the certificate, by which Gemini identifies
+    /// clients.
+    pub fn fingerprint(&self) -> &[u8; 32] {
+        &self.fingerprint
+    }
+
+    /// The fingerprint as lowercase hex.
+    pub fn fingerprint_hex(&self) -> String {
+        self.fingerprint
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn the_fingerprint_is_the_sha256_of_the_der() {
+        let cert = ClientCertificate::new(CertificateDer::from(b"abc".to_vec()));
+        assert_eq!(
+            cert.fingerprint_hex(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+}
@@ -0,0 +1,1074 @@
+//! Configuration, from a TOML file and/or the command line.
+//!
+//! ```toml
+//! port = 1965
+//! workers = 8
+//! capture = "requests.jsonl"
+//!
+//! [timeouts]
+//! read = 5
+//!
+//! [access_log]
+//! path = "access.log"
+//! format = "json"
+//!
+//! [[host]]
+//! hostnames = ["example.org"]
+//! certificate = "example.org.crt"
+//! private_key = "example.org.key"
+//!
+//! [[host.handler]]
+//! type = "static"
+//! path = "blog"
+//! prefix = "/blog/"
+//!
+//! [[host.handler]]
+//! type = "cgi"
+//! path = "cgi-bin"
+//! prefix = "/cgi-bin/"
+//! timeout = 5
+//!
+//! [[host.handler]]
+//! type = "scgi"
+//! backend = "unix:app.sock"
+//! prefix = "/app/"
+//!
+//! [[host.handler]]
+//! type = "proxy"
+//! upstream = "gemini://localhost:1966/"
+//! prefix = "/wiki/"
+//! rewrite = true
+//!
+//! [[host.handler]]
+//! type = "static"
+//! path = "root"
+//! sniff = true
+//! mime_types = { log = "text/plain" }
+//! lang = "en"
+//! charset = "windows-1252"
+//! autoindex = { sizes = true, modified = true, sort = "modified" }
+//! symlinks = "never"
+//! hidden = ["*.bak", "drafts/*"]
+//!
+//! [[host.handler]]
+//! type = "redirect"
+//! gone = ["/drafts/secret.gmi"]
+//! rule = [
+//!     { path = "/old.gmi", to = "/new.gmi", permanent = true },
+//!     { prefix = "/journal/", to = "gemini://blog.example.org/" },
+//!     { regex = '/posts/(\d+)\.gmi', to = "/blog/$1.gmi", example = "/posts/1.gmi" },
+//! ]
+//! ```
+//!
+//! Relative paths are relative to the file they appear in. Handlers are
+//! consulted in the order they are listed.
+
+use std::{
+    collections::HashMap,
+    num::{NonZeroU64, NonZeroUsize},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::Context;
+use serde::Deserialize;
+
+use inimeg::{
+    AccessLog, CgiHandler, Handler, MimeTypes, PoolConfig, ProxyHandler, ScgiHandler, Server,
+    StaticHandler, Timeouts, VirtualHost,
+    access_log::{LogFormat, LogTarget},
+    autoindex::{AutoIndex, SortBy},
+    capture::Recorder,
+    gencert,
+    handler::Symlinks,
+    redirect::{RedirectError, RedirectHandler, RedirectRule},
+    scgi::ScgiBackend,
+    server::DEFAULT_PORT,
+};
+
+use crate::cli;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// The port to listen on.
+    pub port: Option<usize>,
+    /// Number of connections to handle concurrently.
+    pub workers: Option<NonZeroUsize>,
+    /// Number of accepted connections which may wait for a free worker.
+    pub queue_depth: Option<usize>,
+    #[serde(default)]
+    pub timeouts: TimeoutsConfig,
+    /// Where to log responses, if anywhere.
+    pub access_log: Option<AccessLogConfig>,
+    /// A file to record every request to, for `inimeg replay`.
+    pub capture: Option<PathBuf>,
+    #[serde(default, rename = "host")]
+    pub hosts: Vec<HostConfig>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AccessLogConfig {
+    /// A file to append to, rather than stdout.
+    pub path: Option<PathBuf>,
+    #[serde(default)]
+    pub format: LogFormatConfig,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormatConfig {
+    /// Common Log Format.
+    #[default]
+    Common,
+    /// JSON lines.
+    Json,
+}
+
+impl From<LogFormatConfig> for LogFormat {
+    fn from(value: LogFormatConfig) -> Self {
+        match value {
+            LogFormatConfig::Common => Self::Common,
+            LogFormatConfig::Json => Self::Json,
+        }
+    }
+}
+
+/// Timeouts, in seconds. Only the grace period may be zero.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TimeoutsConfig {
+    pub handshake: Option<NonZeroU64>,
+    pub read: Option<NonZeroU64>,
+    pub write: Option<NonZeroU64>,
+    pub shutdown_grace: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HostConfig {
+    /// Names this host answers to. Empty means any name not claimed by
+    /// another host.
+    #[serde(default)]
+    pub hostnames: Vec<String>,
+    pub certificate: PathBuf,
+    pub private_key: PathBuf,
+    /// Generate a self-signed certificate if neither file exists.
+    #[serde(default)]
+    pub auto_cert: bool,
+    #[serde(default, rename = "handler")]
+    pub handlers: Vec<HandlerConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum HandlerConfig {
+    /// Serve files from `path` for requests under `prefix`.
+    Static {
+        path: PathBuf,
+        #[serde(default = "root_prefix")]
+        prefix: String,
+        /// Types by extension, in preference to the built in ones.
+        #[serde(default)]
+        mime_types: HashMap<String, String>,
+        /// Guess the type of files without an extension from their contents.
+        #[serde(default)]
+        sniff: bool,
+        /// The language of gemtext here, where neither its name nor a
+        /// `.lang` file says.
+        lang: Option<String>,
+        /// What text which isn't UTF-8 is encoded in.
+        charset: Option<String>,
+        /// List directories without an index.
+        autoindex: Option<AutoIndexConfig>,
+        /// Which symbolic links to follow.
+        #[serde(default)]
+        symlinks: SymlinksConfig,
+        /// Serve and list files and directories whose names start with a dot.
+        #[serde(default)]
+        show_dotfiles: bool,
+        /// Globs for anything else not to serve.
+        #[serde(default)]
+        hidden: Vec<String>,
+    },
+    /// Run executables in `path` for requests under `prefix`, killing them
+    /// after `timeout` seconds.
+    Cgi {
+        path: PathBuf,
+        #[serde(default = "root_prefix")]
+        prefix: String,
+        timeout: Option<u64>,
+    },
+    /// Forward requests under `prefix` to the SCGI application at `backend`,
+    /// either `host:port` or `unix:path`.
+    Scgi {
+        backend: String,
+        #[serde(default = "root_prefix")]
+        prefix: String,
+        timeout: Option<u64>,
+    },
+    /// Forward requests under `prefix`, and for `host` if given, to the
+    /// Gemini server at `upstream`, rewriting their urls onto it if asked.
+    Proxy {
+        upstream: String,
+        #[serde(default = "root_prefix")]
+        prefix: String,
+        host: Option<String>,
+        #[serde(default)]
+        rewrite: bool,
+        timeout: Option<u64>,
+    },
+    /// Answer requests for moved content with redirects, and for `gone`
+    /// paths with 52.
+    Redirect {
+        #[serde(default, rename = "rule")]
+        rules: Vec<RedirectRuleConfig>,
+        #[serde(default)]
+        gone: Vec<String>,
+    },
+}
+
+/// Requests for `path`, anything under `prefix`, or whatever matches
+/// `regex`, go `to` somewhere else.
+#[derive(Debug, Deserialize)]
+pub struct RedirectRuleConfig {
+    #[serde(flatten)]
+    pub source: RedirectSourceConfig,
+    pub to: String,
+    #[serde(default)]
+    pub permanent: bool,
+    /// A path the rule redirects, to check for loops from.
+    pub example: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RedirectSourceConfig {
+    Path(String),
+    Prefix(String),
+    Regex(String),
+}
+
+impl TryFrom<&RedirectRuleConfig> for RedirectRule {
+    type Error = RedirectError;
+
+    fn try_from(value: &RedirectRuleConfig) -> Result<Self, Self::Error> {
+        let rule = match &value.source {
+            RedirectSourceConfig::Path(path) => Self::exact(path, &value.to),
+            RedirectSourceConfig::Prefix(prefix) => Self::prefix(prefix, &value.to),
+            RedirectSourceConfig::Regex(pattern) => Self::regex(pattern, &value.to)?,
+        };
+        let rule = rule.with_permanent(value.permanent);
+        Ok(match &value.example {
+            Some(example) => rule.with_example(example),
+            None => rule,
+        })
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AutoIndexConfig {
+    #[serde(default)]
+    pub sizes: bool,
+    #[serde(default)]
+    pub modified: bool,
+    #[serde(default)]
+    pub sort: SortConfig,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SortConfig {
+    #[default]
+    Name,
+    Modified,
+    Size,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SymlinksConfig {
+    Never,
+    #[default]
+    WithinRoot,
+    Always,
+}
+
+impl From<&SymlinksConfig> for Symlinks {
+    fn from(value: &SymlinksConfig) -> Self {
+        match value {
+            SymlinksConfig::Never => Self::Never,
+            SymlinksConfig::WithinRoot => Self::WithinRoot,
+            SymlinksConfig::Always => Self::Always,
+        }
+    }
+}
+
+impl From<&AutoIndexConfig> for AutoIndex {
+    fn from(value: &AutoIndexConfig) -> Self {
+        Self {
+            sizes: value.sizes,
+            modified: value.modified,
+            sort: match value.sort {
+                SortConfig::Name => SortBy::Name,
+                SortConfig::Modified => SortBy::Modified,
+                SortConfig::Size => SortBy::Size,
+            },
+        }
+    }
+}
+
+fn root_prefix() -> String {
+    "/".into()
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("Failed to read `{path}`: {source}")]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("Invalid config in `{path}`: {source}")]
+    Parse {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+    #[error(
+        "Host flags need --certificate and --private-key, unless the config file has exactly one \
+         host for them to change"
+    )]
+    NoCertificate,
+    #[error("`{0}` has no file name to mount it under")]
+    NoFileStem(PathBuf),
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let text = std::fs::read_to_string(path).map_err(|source| ConfigError::Read {
+            path: path.into(),
+            source,
+        })?;
+        let mut config: Self = toml::from_str(&text).map_err(|source| ConfigError::Parse {
+            path: path.into(),
+            source,
+        })?;
+        config.resolve_paths(path.parent().unwrap_or(Path::new(".")));
+        Ok(config)
+    }
+
+    fn resolve_paths(&mut self, base: &Path) {
+        if let Some(AccessLogConfig {
+            path: Some(path), ..
+        }) = &mut self.access_log
+        {
+            *path = base.join(&*path);
+        }
+        if let Some(path) = &mut self.capture {
+            *path = base.join(&*path);
+        }
+        for host in &mut self.hosts {
+            host.certificate = base.join(&host.certificate);
+            host.private_key = base.join(&host.private_key);
+            for handler in &mut host.handlers {
+                match handler {
+                    HandlerConfig::Static { path, .. } | HandlerConfig::Cgi { path, .. } => {
+                        *path = base.join(&*path)
+                    }
+                    HandlerConfig::Proxy { .. } | HandlerConfig::Redirect { .. } => {}
+                    HandlerConfig::Scgi { backend, .. } => {
+                        if let ScgiBackend::Unix(path) = ScgiBackend::from(backend.as_str()) {
+                            *backend = format!("unix:{}", base.join(path).display());
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Take settings given on the command line in preference to ours, and
+    /// add any hosts it describes after our own.
+    ///
+    /// Host flags without a certificate change our host instead, if we have
+    /// just the one.
+    pub fn merge_cli(mut self, cli: &cli::Serve) -> Result<Self, ConfigError> {
+        self.port = cli.port.or(self.port);
+        self.workers = cli.workers.or(self.workers);
+        self.queue_depth = cli.queue_depth.or(self.queue_depth);
+        let timeouts = &mut self.timeouts;
+        timeouts.handshake = cli.handshake_timeout.or(timeouts.handshake);
+        timeouts.read = cli.read_timeout.or(timeouts.read);
+        timeouts.write = cli.write_timeout.or(timeouts.write);
+        timeouts.shutdown_grace = cli.shutdown_grace.or(timeouts.shutdown_grace);
+        if cli.access_log.is_some() || cli.access_log_format.is_some() {
+            let access_log = self.access_log.get_or_insert_default();
+            if let Some(path) = &cli.access_log {
+                access_log.path = (path != Path::new("-")).then(|| path.clone());
+            }
+            access_log.format = cli.access_log_format.unwrap_or(access_log.format);
+        }
+        self.capture = cli.capture.clone().or(self.capture);
+
+        let mut handlers = cli
+            .static_dirs
+            .iter()
+            .flatten()
+            .map(|path| {
+                let stem = path
+                    .file_stem()
+                    .ok_or_else(|| ConfigError::NoFileStem(path.clone()))?;
+                let prefix = stem.to_string_lossy().into_owned();
+                Ok(HandlerConfig::static_dir(path.clone(), prefix))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        handlers.extend(
+            cli.root_dir
+                .iter()
+                .map(|path| HandlerConfig::static_dir(path.clone(), root_prefix())),
+        );
+        match (&cli.certificate, &cli.private_key) {
+            (Some(certificate), Some(private_key)) => self.hosts.push(HostConfig {
+                hostnames: cli.hostname.clone(),
+                certificate: certificate.clone(),
+                private_key: private_key.clone(),
+                auto_cert: cli.auto_cert,
+                handlers,
+            }),
+            _ if handlers.is_empty() && cli.hostname.is_empty() && !cli.auto_cert => {}
+            _ => {
+                let [host] = &mut self.hosts[..] else {
+                    return Err(ConfigError::NoCertificate);
+                };
+                if !cli.hostname.is_empty() {
+                    host.hostnames = cli.hostname.clone();
+                }
+                host.auto_cert |= cli.auto_cert;
+                host.handlers.extend(handlers);
+            }
+        }
+        self.hosts.extend(cli.vhost.iter().map(|vhost| HostConfig {
+            hostnames: vec![vhost.hostname.clone()],
+            certificate: vhost.certificate.clone(),
+            private_key: vhost.private_key.clone(),
+            auto_cert: false,
+            handlers: vec![HandlerConfig::static_dir(
+                vhost.root_dir.clone(),
+                root_prefix(),
+            )],
+        }));
+        Ok(self)
+    }
+
+    pub fn port(&self) -> usize {
+        self.port.unwrap_or(DEFAULT_PORT)
+    }
+
+    pub fn pool(&self) -> PoolConfig {
+        let default = PoolConfig::default();
+        PoolConfig {
+            workers: self.workers.unwrap_or(default.workers),
+            queue_depth: self.queue_depth.unwrap_or(default.queue_depth),
+        }
+    }
+
+    pub fn timeouts(&self) -> Timeouts {
+        let default = Timeouts::default();
+        let seconds = |value: Option<u64>, default| value.map_or(default, Duration::from_secs);
+        let timeouts = &self.timeouts;
+        Timeouts {
+            handshake: seconds(timeouts.handshake.map(NonZeroU64::get), default.handshake),
+            read: seconds(timeouts.read.map(NonZeroU64::get), default.read),
+            write: seconds(timeouts.write.map(NonZeroU64::get), default.write),
+            grace: seconds(timeouts.shutdown_grace, default.grace),
+        }
+    }
+
+    /// Open the access log, if we keep one.
+    pub fn access_log(&self) -> anyhow::Result<Option<AccessLog>> {
+        let Some(config) = &self.access_log else {
+            return Ok(None);
+        };
+        let target = match &config.path {
+            Some(path) => LogTarget::File(path.clone()),
+            None => LogTarget::Stdout,
+        };
+        let access_log = AccessLog::open(target, config.format.into())
+            .with_context(|| format!("Access log {:?}", config.path))?;
+        Ok(Some(access_log))
+    }
+
+    /// A server for everything we describe, ready to run.
+    pub fn server(&self) -> anyhow::Result<Server> {
+        let mut builder = Server::builder()
+            .port(self.port())
+            .pool(self.pool())
+            .timeouts(self.timeouts());
+        if let Some(access_log) = self.access_log()? {
+            builder = builder.access_log(access_log);
+        }
+        if let Some(path) = &self.capture {
+            let capture = Recorder::open(path).with_context(|| format!("Capture {path:?}"))?;
+            builder = builder.capture(capture);
+        }
+        self.build_hosts()?
+            .into_iter()
+            .fold(builder, |builder, host| builder.host(host))
+            .build()
+    }
+
+    /// Build every host, in order.
+    pub fn build_hosts(&self) -> anyhow::Result<Vec<VirtualHost>> {
+        self.build_each(HostConfig::build)
+    }
+
+    /// Build every host without its certificate, to answer requests which
+    /// don't arrive over TLS. No key is read or generated.
+    pub fn build_hosts_without_certificates(&self) -> anyhow::Result<Vec<VirtualHost>> {
+        self.build_each(|host| {
+            let mut virtual_host = VirtualHost::without_certificate(&host.hostnames);
+            for handler in host.build_handlers()? {
+                virtual_host.add_handler(handler);
+            }
+            Ok(virtual_host)
+        })
+    }
+
+    fn build_each(
+        &self,
+        build: impl Fn(&HostConfig) -> anyhow::Result<VirtualHost>,
+    ) -> anyhow::Result<Vec<VirtualHost>> {
+        self.hosts
+            .iter()
+            .enumerate()
+            .map(|(i, host)| {
+                build(host).with_context(|| format!("Host {} ({:?})", i + 1, host.hostnames))
+            })
+            .collect()
+    }
+}
+
+impl HostConfig {
+    pub fn build(&self) -> anyhow::Result<VirtualHost> {
+        if self.auto_cert {
+            let hostnames = match self.hostnames.as_slice() {
+                [] => &["localhost".into()],
+                hostnames => hostnames,
+            };
+            gencert::ensure(&self.certificate, &self.private_key, hostnames)?;
+        }
+        let mut host =
+            VirtualHost::from_pem_files(&self.hostnames, &self.certificate, &self.private_key)?;
+        for handler in self.build_handlers()? {
+            host.add_handler(handler);
+        }
+        Ok(host)
+    }
+
+    /// Build our handlers, in order.
+    pub fn build_handlers(&self) -> anyhow::Result<Vec<Box<dyn Handler>>> {
+        self.handlers
+            .iter()
+            .enumerate()
+            .map(|(i, handler)| {
+                handler
+                    .build(&self.hostnames)
+                    .with_context(|| format!("Handler {}", i + 1))
+            })
+            .collect()
+    }
+}
+
+impl HandlerConfig {
+    /// Files served with the default types, as from the command line.
+    fn static_dir(path: PathBuf, prefix: String) -> Self {
+        Self::Static {
+            path,
+            prefix,
+            mime_types: HashMap::new(),
+            sniff: false,
+            lang: None,
+            charset: None,
+            autoindex: None,
+            symlinks: SymlinksConfig::default(),
+            show_dotfiles: false,
+            hidden: Vec::new(),
+        }
+    }
+
+    /// Build the handler for a host answering to `hostnames`.
+    pub fn build(&self, hostnames: &[String]) -> anyhow::Result<Box<dyn Handler>> {
+        Ok(match self {
+            Self::Static {
+                path,
+                prefix,
+                mime_types,
+                sniff,
+                lang,
+                charset,
+                autoindex,
+                symlinks,
+                show_dotfiles,
+                hidden,
+            } => {
+                let path = path
+                    .canonicalize()
+                    .with_context(|| format!("Static dir {path:?}"))?;
+                let mime_types = mime_types
+                    .iter()
+                    .fold(MimeTypes::default(), |types, (extension, mime)| {
+                        types.with_override(extension, mime.as_str())
+                    })
+                    .with_sniffing(*sniff);
+                let mut handler = StaticHandler::new(path, prefix)?
+                    .with_mime_types(mime_types)
+                    .with_lang(lang.clone())
+                    .with_autoindex(autoindex.as_ref().map(AutoIndex::from))
+                    .with_symlinks(symlinks.into())
+                    .with_hidden_dotfiles(!show_dotfiles);
+                for pattern in hidden {
+                    handler = handler.with_hidden_pattern(pattern)?;
+                }
+                if let Some(charset) = charset {
+                    handler = handler.with_fallback_charset(charset);
+                }
+                Box::new(handler)
+            }
+            Self::Cgi {
+                path,
+                prefix,
+                timeout,
+            } => {
+                let path = path
+                    .canonicalize()
+                    .with_context(|| format!("CGI dir {path:?}"))?;
+                let handler = CgiHandler::new(path, prefix)?;
+                Box::new(match timeout {
+                    Some(seconds) => handler.with_timeout(Duration::from_secs(*seconds)),
+                    None => handler,
+                })
+            }
+            Self::Scgi {
+                backend,
+                prefix,
+                timeout,
+            } => {
+                let handler = ScgiHandler::new(backend.as_str().into(), prefix);
+                Box::new(match timeout {
+                    Some(seconds) => handler.with_timeout(Duration::from_secs(*seconds)),
+                    None => handler,
+                })
+            }
+            Self::Proxy {
+                upstream,
+                prefix,
+                host,
+                rewrite,
+                timeout,
+            } => {
+                let upstream = upstream
+                    .parse()
+                    .with_context(|| format!("Upstream {upstream:?}"))?;
+                let mut handler = ProxyHandler::new(upstream, prefix)?.with_rewrite(*rewrite);
+                if let Some(host) = host {
+                    handler = handler.for_host(host);
+                }
+                Box::new(match timeout {
+                    Some(seconds) => handler.with_timeout(Duration::from_secs(*seconds)),
+                    None => handler,
+                })
+            }
+            Self::Redirect { rules, gone } => {
+                let rules = rules
+                    .iter()
+                    .map(RedirectRule::try_from)
+                    .collect::<Result<_, _>>()?;
+                Box::new(RedirectHandler::new(rules, gone)?.with_hosts(hostnames)?)
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use anyhow::Result;
+    use clap::Parser;
+    use rstest::rstest;
+    use tempfile::TempDir;
+
+    fn write(dir: &TempDir, config: &str) -> Result<PathBuf> {
+        let path = dir.path().join("inimeg.toml");
+        std::fs::write(&path, config)?;
+        Ok(path)
+    }
+
+    fn serve(args: &[&str]) -> cli::Serve {
+        let args = ["inimeg", "serve"].iter().chain(args);
+        match cli::Cli::parse_from(args) {
+            cli::Cli::Serve(serve) => *serve,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn a_config_file_is_parsed() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = write(
+            &dir,
+            r#"
+port = 1966
+workers = 2
+
+[timeouts]
+read = 5
+
+[[host]]
+hostnames = ["example.org"]
+certificate = "cert.pem"
+private_key = "/etc/key.pem"
+
+[[host.handler]]
+type = "static"
+path = "blog"
+prefix = "/blog/"
+
+[[host.handler]]
+type = "cgi"
+path = "cgi-bin"
+prefix = "/cgi-bin/"
+timeout = 5
+
+[[host.handler]]
+type = "scgi"
+backend = "unix:app.sock"
+
+[[host.handler]]
+type = "static"
+path = "root"
+sniff = true
+mime_types = { log = "text/plain" }
+lang = "en"
+autoindex = { sort = "size" }
+symlinks = "never"
+show_dotfiles = true
+hidden = ["*.bak"]
+"#,
+        )?;
+
+        let config = Config::load(&path)?;
+
+        assert_eq!(config.port(), 1966);
+        assert_eq!(config.pool().workers.get(), 2);
+        assert_eq!(config.pool().queue_depth, PoolConfig::default().queue_depth);
+        assert_eq!(config.timeouts().read, Duration::from_secs(5));
+        let host = &config.hosts[0];
+        assert_eq!(host.certificate, dir.path().join("cert.pem"));
+        assert_eq!(host.private_key, PathBuf::from("/etc/key.pem"));
+        assert!(matches!(
+            &host.handlers[..],
+            [
+                HandlerConfig::Static { path: blog, prefix: blog_prefix, sniff: false, .. },
+                HandlerConfig::Cgi { path: cgi, timeout: Some(5), .. },
+                HandlerConfig::Scgi { backend, .. },
+                HandlerConfig::Static {
+                    prefix: root_prefix,
+                    mime_types,
+                    sniff: true,
+                    lang: Some(lang),
+                    autoindex: Some(AutoIndexConfig {
+                        sort: SortConfig::Size,
+                        ..
+                    }),
+                    symlinks: SymlinksConfig::Never,
+                    show_dotfiles: true,
+                    hidden,
+                    ..
+                },
+            ] if *blog == dir.path().join("blog")
+                && blog_prefix == "/blog/"
+                && *cgi == dir.path().join("cgi-bin")
+                && *backend == format!("unix:{}", dir.path().join("app.sock").display())
+                && root_prefix == "/"
+                && mime_types["log"] == "text/plain"
+                && lang == "en"
+                && hidden == &["*.bak"]
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn redirects_are_checked_when_built() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = write(
+            &dir,
+            r#"
+[[host]]
+certificate = "cert.pem"
+private_key = "key.pem"
+
+[[host.handler]]
+type = "redirect"
+gone = ["/removed.gmi"]
+rule = [
+    { path = "/a.gmi", to = "/b.gmi", permanent = true },
+    { prefix = "/old/", to = "gemini://example.org/" },
+    { regex = '/(\d+)', to = "/posts/$1.gmi", example = "/1" },
+]
+
+[[host.handler]]
+type = "redirect"
+rule = [
+    { path = "/a.gmi", to = "/b.gmi" },
+    { path = "/b.gmi", to = "/a.gmi" },
+]
+"#,
+        )?;
+
+        let config = Config::load(&path)?;
+
+        let [redirects, looping] = &config.hosts[0].handlers[..] else {
+            panic!("{:?}", config.hosts[0].handlers);
+        };
+        assert!(matches!(
+            redirects,
+            HandlerConfig::Redirect { rules, gone }
+                if matches!(
+                    &rules[..],
+                    [
+                        RedirectRuleConfig { source: RedirectSourceConfig::Path(_), permanent: true, .. },
+                        RedirectRuleConfig { source: RedirectSourceConfig::Prefix(_), permanent: false, .. },
+                        RedirectRuleConfig { source: RedirectSourceConfig::Regex(_), example: Some(_), .. },
+                    ]
+                ) && gone == &["/removed.gmi"]
+        ));
+        redirects.build(&[])?;
+        let err = looping.build(&[]).err().expect("loop").to_string();
+        assert!(err.contains("lead back"), "{err}");
+        Ok(())
+    }
+
+    #[test]
+    fn the_documented_example_is_valid() -> Result<()> {
+        let example = include_str!("config.rs")
+            .lines()
+            .map_while(|line| line.strip_prefix("//!"))
+            .skip_while(|line| *line != " ```toml")
+            .skip(1)
+            .take_while(|line| *line != " ```")
+            .map(|line| line.strip_prefix(' ').unwrap_or(line))
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        let config: Config = toml::from_str(&example)?;
+
+        assert_eq!(config.capture, Some(PathBuf::from("requests.jsonl")));
+        Ok(())
+    }
+
+    #[test]
+    fn errors_give_the_line_and_column() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = write(&dir, "port = 1965\n\n[timeouts]\nread = \"soon\"\n")?;
+
+        let err = Config::load(&path).unwrap_err().to_string();
+
+        assert!(err.contains("inimeg.toml"), "{err}");
+        assert!(err.contains("line 4, column 8"), "{err}");
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = write(
+            &dir,
+            "[[host]]\ncertificate = \"a\"\nprivate_key = \"b\"\nroot = \"/\"\n",
+        )?;
+
+        let err = Config::load(&path).unwrap_err().to_string();
+
+        assert!(err.contains("line 4"), "{err}");
+        assert!(err.contains("root"), "{err}");
+        Ok(())
+    }
+
+    #[test]
+    fn zero_timeouts_are_rejected() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = write(&dir, "[timeouts]\nwrite = 0\nshutdown_grace = 0\n")?;
+
+        let err = Config::load(&path).unwrap_err().to_string();
+        assert!(err.contains("line 2"), "{err}");
+
+        let args = ["inimeg", "serve", "--config", "x", "--read-timeout", "0"];
+        assert!(cli::Cli::try_parse_from(args).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn command_line_flags_override_the_file() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = write(&dir, "port = 1966\nworkers = 2\n[timeouts]\nread = 5\n")?;
+
+        let config = Config::load(&path)?.merge_cli(&serve(&[
+            "--config",
+            "x",
+            "--port",
+            "1967",
+            "--read-timeout",
+            "7",
+        ]))?;
+
+        assert_eq!(config.port(), 1967);
+        assert_eq!(config.pool().workers.get(), 2);
+        assert_eq!(config.timeouts().read, Duration::from_secs(7));
+        Ok(())
+    }
+
+    #[test]
+    fn the_access_log_may_be_configured_or_overridden() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = write(&dir, "[access_log]\npath = \"logs/access.log\"\n")?;
+
+        let config = Config::load(&path)?;
+        assert!(matches!(
+            &config.access_log,
+            Some(AccessLogConfig { path: Some(path), format: LogFormatConfig::Common })
+                if *path == dir.path().join("logs/access.log")
+        ));
+
+        let config = config.merge_cli(&serve(&[
+            "--config",
+            "x",
+            "--access-log",
+            "-",
+            "--access-log-format",
+            "json",
+        ]))?;
+        assert!(matches!(
+            config.access_log,
+            Some(AccessLogConfig {
+                path: None,
+                format: LogFormatConfig::Json
+            })
+        ));
+        assert!(Config::default().access_log()?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn the_capture_file_may_be_configured_or_overridden() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = write(&dir, "capture = \"requests.jsonl\"\n")?;
+
+        let config = Config::load(&path)?;
+        assert_eq!(config.capture, Some(dir.path().join("requests.jsonl")));
+
+        let config = config.merge_cli(&serve(&["--config", "x", "--capture", "other.jsonl"]))?;
+        assert_eq!(config.capture, Some(PathBuf::from("other.jsonl")));
+        Ok(())
+    }
+
+    #[test]
+    fn command_line_hosts_follow_the_files() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = write(
+            &dir,
+            "[[host]]\nhostnames = [\"a\"]\ncertificate = \"a.crt\"\nprivate_key = \"a.key\"\n",
+        )?;
+
+        let config = Config::load(&path)?.merge_cli(&serve(&[
+            "--certificate",
+            "b.crt",
+            "--private-key",
+            "b.key",
+            "--static-dirs",
+            "foo/tinylog",
+            "--vhost",
+            "c:c.crt:c.key:c",
+        ]))?;
+
+        let names = config
+            .hosts
+            .iter()
+            .map(|host| host.hostnames.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(names, [vec!["a"], vec![], vec!["c"]]);
+        assert!(matches!(
+            &config.hosts[1].handlers[..],
+            [HandlerConfig::Static { prefix, .. }] if prefix == "tinylog"
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn host_flags_without_a_certificate_change_the_file_host() -> Result<()> {
+        let dir = TempDir::new()?;
+        let host =
+            "[[host]]\nhostnames = [\"a\"]\ncertificate = \"a.crt\"\nprivate_key = \"a.key\"\n";
+        let path = write(&dir, host)?;
+        let flags = ["--config", "x", "--hostname", "b", "--root-dir", "public"];
+
+        let config = Config::load(&path)?.merge_cli(&serve(&flags))?;
+        assert_eq!(config.hosts.len(), 1);
+        assert_eq!(config.hosts[0].hostnames, ["b"]);
+        assert!(matches!(
+            &config.hosts[0].handlers[..],
+            [HandlerConfig::Static { prefix, .. }] if prefix == "/"
+        ));
+
+        let path = write(&dir, &format!("{host}{host}"))?;
+        let err = Config::load(&path)?.merge_cli(&serve(&flags));
+        assert!(matches!(err, Err(ConfigError::NoCertificate)));
+        Ok(())
+    }
+
+    #[test]
+    fn hosts_built_without_certificates_leave_keys_alone() -> Result<()> {
+        let dir = TempDir::new()?;
+        std::fs::create_dir(dir.path().join("public"))?;
+        let path = write(
+            &dir,
+            r#"
+[[host]]
+hostnames = ["example.org"]
+certificate = "example.org.crt"
+private_key = "example.org.key"
+auto_cert = true
+
+[[host.handler]]
+type = "static"
+path = "public"
+prefix = "/"
+"#,
+        )?;
+
+        let hosts = Config::load(&path)?.build_hosts_without_certificates()?;
+
+        assert_eq!(hosts.len(), 1);
+        assert_eq!(hosts[0].handlers().len(), 1);
+        assert!(!dir.path().join("example.org.crt").exists());
+        assert!(!dir.path().join("example.org.key").exists());
+        Ok(())
+    }
+
+    #[rstest]
+    #[case("/")]
+    #[case("..")]
+    fn static_dirs_need_a_name_to_mount_under(#[case] dir: &str) {
+        let flags = [
+            "--static-dirs",
+            dir,
+            "--certificate",
+            "a.crt",
+            "--private-key",
+            "a.key",
+        ];
+
+        let err = Config::default().merge_cli(&serve(&flags));
+
+        assert!(matches!(err, Err(ConfigError::NoFileStem(path)) if path == Path::new(dir)));
+    }
+}
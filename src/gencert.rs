@@ -0,0 +1,231 @@
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use log::info;
+use rcgen::{CertificateParams, DnType, KeyPair};
+
+/// Clients trusting on first use will complain when a certificate changes, so
+/// we make them last about a century.
+pub const DEFAULT_DAYS: u32 = 36500;
+
+/// A freshly minted self-signed certificate and its key, PEM encoded.
+pub struct SelfSigned {
+    pub certificate: String,
+    pub private_key: String,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum GencertError {
+    #[error("At least one hostname is required")]
+    NoHostnames,
+    #[error("A certificate can't be valid for {0} days")]
+    TooLong(u32),
+    #[error("Failed to generate certificate: {0}")]
+    Generate(#[from] rcgen::Error),
+    #[error("Refusing to overwrite `{0}`")]
+    Exists(PathBuf),
+    #[error("Only one of `{certificate}` and `{private_key}` exists, refusing to replace it")]
+    Incomplete {
+        certificate: PathBuf,
+        private_key: PathBuf,
+    },
+    #[error("Failed to write `{path}`: {source}")]
+    Write {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+}
+
+impl SelfSigned {
+    /// Generate a certificate valid for `days` from now.
+    ///
+    /// The first hostname becomes the common name, and all of them (IP
+    /// addresses included) subject alternative names.
+    pub fn generate(hostnames: &[String], days: u32) -> Result<Self, GencertError> {
+        let common_name = hostnames.first().ok_or(GencertError::NoHostnames)?;
+        let mut params = CertificateParams::new(hostnames)?;
+        params
+            .distinguished_name
+            .push(DnType::CommonName, common_name);
+        let now = time::OffsetDateTime::now_utc();
+        params.not_before = now;
+        params.not_after = now
+            .checked_add(time::Duration::days(days.into()))
+            .ok_or(GencertError::TooLong(days))?;
+
+        let key = KeyPair::generate()?;
+        let certificate = params.self_signed(&key)?;
+        Ok(Self {
+            certificate: certificate.pem(),
+            private_key: key.serialize_pem(),
+        })
+    }
+
+    /// Write the certificate and key out, the key readable only by us.
+    pub fn write(
+        &self,
+        certificate: &Path,
+        private_key: &Path,
+        overwrite: bool,
+    ) -> Result<(), GencertError> {
+        if !overwrite {
+            for path in [certificate, private_key] {
+                if path.exists() {
+                    return Err(GencertError::Exists(path.into()));
+                }
+            }
+        }
+        write_file(certificate, &self.certificate, 0o644)?;
+        write_file(private_key, &self.private_key, 0o600)
+    }
+}
+
+/// Generate a certificate and key for `hostnames`, unless we already have them.
+pub fn ensure(
+    certificate: &Path,
+    private_key: &Path,
+    hostnames: &[String],
+) -> Result<(), GencertError> {
+    match (certificate.exists(), private_key.exists()) {
+        (true, true) => Ok(()),
+        (false, false) => {
+            info!("Generating a self-signed certificate for {hostnames:?} at {certificate:?}");
+            SelfSigned::generate(hostnames, DEFAULT_DAYS)?.write(certificate, private_key, false)
+        }
+        _ => Err(GencertError::Incomplete {
+            certificate: certificate.into(),
+            private_key: private_key.into(),
+        }),
+    }
+}
+
+fn write_file(path: &Path, contents: &str, mode: u32) -> Result<(), GencertError> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, mode);
+    #[cfg(not(unix))]
+    let _ = mode;
+    options
+        .open(path)
+        .and_then(|mut file| {
+            // The mode above only applies to new files, and we may be
+            // overwriting one anybody can read.
+            #[cfg(unix)]
+            file.set_permissions(std::os::unix::fs::PermissionsExt::from_mode(mode))?;
+            file.write_all(contents.as_bytes())
+        })
+        .map_err(|source| GencertError::Write {
+            path: path.into(),
+            source,
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::identity::Identity;
+    use anyhow::Result;
+    use tempfile::TempDir;
+
+    fn hostnames(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn generated_files_can_be_served() -> Result<()> {
+        let dir = TempDir::new()?;
+        let (cert, key) = (dir.path().join("cert.pem"), dir.path().join("key.pem"));
+
+        SelfSigned::generate(&hostnames(&["example.com", "127.0.0.1"]), 365)?
+            .write(&cert, &key, false)?;
+
+        let identity = Identity::from_pem_files(&cert, &key)?;
+        assert_eq!(identity.chain.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn existing_files_are_not_overwritten_unless_asked() -> Result<()> {
+        let dir = TempDir::new()?;
+        let (cert, key) = (dir.path().join("cert.pem"), dir.path().join("key.pem"));
+        std::fs::write(&key, "precious")?;
+        let generated = SelfSigned::generate(&hostnames(&["example.com"]), 365)?;
+
+        assert!(matches!(
+            generated.write(&cert, &key, false),
+            Err(GencertError::Exists(_))
+        ));
+        assert_eq!(std::fs::read_to_string(&key)?, "precious");
+
+        generated.write(&cert, &key, true)?;
+        assert_eq!(std::fs::read_to_string(&key)?, generated.private_key);
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn the_private_key_is_private() -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = TempDir::new()?;
+        let (cert, key) = (dir.path().join("cert.pem"), dir.path().join("key.pem"));
+        SelfSigned::generate(&hostnames(&["example.com"]), 365)?.write(&cert, &key, false)?;
+
+        assert_eq!(std::fs::metadata(&key)?.permissions().mode() & 0o777, 0o600);
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn an_overwritten_private_key_is_made_private() -> Result<()> {
+        use std::{fs::Permissions, os::unix::fs::PermissionsExt};
+
+        let dir = TempDir::new()?;
+        let (cert, key) = (dir.path().join("cert.pem"), dir.path().join("key.pem"));
+        std::fs::write(&key, "old key")?;
+        std::fs::set_permissions(&key, Permissions::from_mode(0o644))?;
+
+        SelfSigned::generate(&hostnames(&["example.com"]), 365)?.write(&cert, &key, true)?;
+
+        assert_eq!(std::fs::metadata(&key)?.permissions().mode() & 0o777, 0o600);
+        Ok(())
+    }
+
+    #[test]
+    fn ensure_only_generates_missing_files() -> Result<()> {
+        let dir = TempDir::new()?;
+        let (cert, key) = (dir.path().join("cert.pem"), dir.path().join("key.pem"));
+
+        ensure(&cert, &key, &hostnames(&["example.com"]))?;
+        let generated = std::fs::read_to_string(&cert)?;
+        ensure(&cert, &key, &hostnames(&["example.com"]))?;
+        assert_eq!(std::fs::read_to_string(&cert)?, generated);
+
+        std::fs::remove_file(&key)?;
+        assert!(matches!(
+            ensure(&cert, &key, &hostnames(&["example.com"])),
+            Err(GencertError::Incomplete { .. })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn validity_must_end_in_a_representable_year() {
+        assert!(matches!(
+            SelfSigned::generate(&hostnames(&["example.com"]), u32::MAX),
+            Err(GencertError::TooLong(u32::MAX))
+        ));
+    }
+
+    #[test]
+    fn a_hostname_is_required() {
+        assert!(matches!(
+            SelfSigned::generate(&[], 365),
+            Err(GencertError::NoHostnames)
+        ));
+    }
+}
@@ -1,23 +1,33 @@
 use std::{
+    ffi::OsStr,
     fs::File,
     ops::Deref,
     path::{Path, PathBuf},
 };
 
-use log::debug;
+use glob::{MatchOptions, Pattern};
+use log::{debug, warn};
+use percent_encoding::percent_decode_str;
+use url::Url;
 
 use crate::{
+    autoindex::AutoIndex,
+    mime::{self, Mime, MimeTypes},
     request::Request,
-    response::{ErrResponse, FileResponse, Response},
-    status::{Status, Success},
+    response::{ErrResponse, FileResponse, Response, SuccessResponse},
+    status::{PermanentFailure, Redirect, Status, Success},
 };
 
-pub trait Handler {
-    fn handle_request(&mut self, request: &Request) -> Option<Response>;
+/// Something which may answer a request.
+///
+/// Handlers are shared between worker threads, so they must be `Send + Sync`
+/// and may not mutate themselves whilst handling a request.
+pub trait Handler: Send + Sync {
+    fn handle_request(&self, request: &Request) -> Option<Response>;
 }
 
 #[derive(Debug)]
-struct Prefix(String);
+pub(crate) struct Prefix(String);
 
 impl From<String> for Prefix {
     fn from(mut value: String) -> Self {
@@ -38,20 +48,118 @@ impl Deref for Prefix {
     }
 }
 
+/// Which symbolic links a [`StaticHandler`] follows.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Symlinks {
+    Never,
+    /// Only those leading somewhere inside the content dir.
+    #[default]
+    WithinRoot,
+    /// Any, wherever they lead.
+    Always,
+}
+
 #[derive(Debug)]
 pub struct StaticHandler {
     /// The directory containing the content
     path: PathBuf,
     /// The prefix required in the url for this handler to match.
     prefix: Prefix,
+    mime_types: MimeTypes,
+    autoindex: Option<AutoIndex>,
+    /// The language of gemtext here, unless a file or directory says
+    /// otherwise.
+    lang: Option<String>,
+    /// What we say text which isn't UTF-8 is encoded in.
+    fallback_charset: String,
+    symlinks: Symlinks,
+    /// Refuse files and directories whose names start with a dot.
+    hide_dotfiles: bool,
+    /// Refuse anything matching one of these.
+    hidden: Vec<Pattern>,
+}
+
+/// Files served in place of a directory, in order of preference.
+const INDEX_FILES: [&str; 2] = ["index.gemini", "index.gmi"];
+
+/// Send the client to the directory it meant.
+fn add_slash(url: &Url) -> Response {
+    let mut url = url.clone();
+    url.set_path(&format!("{}/", url.path()));
+    Response::Err(ErrResponse {
+        status: Status::Redirect(Redirect::Permanent),
+        msg: Some(url.to_string().into()),
+    })
+}
+
+fn bad_request() -> Response {
+    Response::Err(ErrResponse::from_status(Status::PermanentFailure(
+        PermanentFailure::BadRequest,
+    )))
+}
+
+fn not_found() -> Response {
+    Response::Err(ErrResponse::from_status(Status::PermanentFailure(
+        PermanentFailure::NotFound,
+    )))
 }
 
+/// A file naming the language of the gemtext in its directory and those
+/// below it.
+pub const LANG_FILE: &str = ".lang";
+
+/// What we assume text which isn't UTF-8 to be.
+pub const DEFAULT_FALLBACK_CHARSET: &str = "iso-8859-1";
+
 #[derive(Debug, thiserror::Error)]
 pub enum StaticHandlerError {
     #[error("Path is relative")]
     RelativePath,
     #[error("Path does not existt")]
     MissingPath,
+    #[error("Invalid pattern `{pattern}`: {source}")]
+    Pattern {
+        pattern: String,
+        source: glob::PatternError,
+    },
+}
+
+/// Why a url path can't name a file.
+#[derive(Debug, PartialEq, thiserror::Error)]
+pub(crate) enum BadPath {
+    #[error("Path climbs to a parent directory")]
+    Parent,
+    #[error("Path contains a NUL")]
+    Nul,
+    #[error("Path segment contains an encoded separator")]
+    EncodedSeparator,
+    #[error("Path is not UTF-8")]
+    NotUtf8,
+}
+
+/// Percent-decode the url path `path` into a relative file path, one
+/// segment at a time.
+///
+/// Empty and `.` segments are dropped. Anything which could step outside
+/// the directory it's joined to, or be read differently by the OS, is
+/// refused.
+pub(crate) fn decode_path(path: &str) -> Result<PathBuf, BadPath> {
+    let mut decoded = PathBuf::new();
+    for segment in path.split('/') {
+        let segment = percent_decode_str(segment)
+            .decode_utf8()
+            .map_err(|_| BadPath::NotUtf8)?;
+        match &*segment {
+            "" | "." => continue,
+            ".." => return Err(BadPath::Parent),
+            _ if segment.contains('\0') => return Err(BadPath::Nul),
+            _ if segment.contains(std::path::is_separator) => {
+                return Err(BadPath::EncodedSeparator);
+            }
+            _ => decoded.push(&*segment),
+        }
+    }
+    Ok(decoded)
 }
 
 impl StaticHandler {
@@ -61,86 +169,268 @@ impl StaticHandler {
     ) -> Result<Self, StaticHandlerError> {
         let path: PathBuf = path.into();
         if !path.is_absolute() {
-            Err(StaticHandlerError::RelativePath)
-        } else if !path.exists() {
-            Err(StaticHandlerError::MissingPath)
-        } else {
-            Ok(Self {
+            return Err(StaticHandlerError::RelativePath);
+        }
+        match path.canonicalize() {
+            Err(_) => Err(StaticHandlerError::MissingPath),
+            Ok(path) => Ok(Self {
                 path,
                 prefix: Prefix::from(prefix.into()),
-            })
+                mime_types: MimeTypes::default(),
+                autoindex: None,
+                lang: None,
+                fallback_charset: DEFAULT_FALLBACK_CHARSET.into(),
+                symlinks: Symlinks::default(),
+                hide_dotfiles: true,
+                hidden: Vec::new(),
+            }),
         }
     }
-}
 
-fn mime_type(path: &Path) -> &'static str {
-    match path.extension().and_then(|s| s.to_str()) {
-        Some("gemini") => "text/gemini",
-        Some("gmi") => "text/gemini",
-        // Better to guess wrong for now
-        _ => "text/plain",
+    pub fn with_mime_types(mut self, mime_types: MimeTypes) -> Self {
+        self.mime_types = mime_types;
+        self
     }
-}
 
-impl Handler for StaticHandler {
-    fn handle_request(&mut self, request: &Request) -> Option<Response> {
-        let url = request.url();
-        (url.query().is_none())
-            .then_some(url.path())
-            .inspect(|path| {
-                debug!(
-                    "Static handler for '{:?}' is considering request for '{}'",
-                    self.prefix, path
-                )
+    /// List directories without an index.
+    pub fn with_autoindex(mut self, autoindex: Option<AutoIndex>) -> Self {
+        self.autoindex = autoindex;
+        self
+    }
+
+    /// Declare gemtext here to be in `lang`, if it's a language tag.
+    pub fn with_lang(mut self, lang: Option<String>) -> Self {
+        self.lang = lang.filter(|lang| {
+            let valid = mime::is_lang_tag(lang);
+            if !valid {
+                warn!("Ignoring {lang:?}, which isn't a language tag");
+            }
+            valid
+        });
+        self
+    }
+
+    /// Say text which isn't UTF-8 is in `charset`, if it's a charset name.
+    pub fn with_fallback_charset(mut self, charset: impl Into<String>) -> Self {
+        let charset = charset.into();
+        if mime::is_charset(&charset) {
+            self.fallback_charset = charset;
+        } else {
+            warn!("Ignoring {charset:?}, which isn't a charset");
+        }
+        self
+    }
+
+    pub fn with_symlinks(mut self, symlinks: Symlinks) -> Self {
+        self.symlinks = symlinks;
+        self
+    }
+
+    /// Whether to refuse dotfiles, and everything in dot-directories, as
+    /// we do by default. Listings leave out whatever we refuse.
+    pub fn with_hidden_dotfiles(mut self, hide: bool) -> Self {
+        self.hide_dotfiles = hide;
+        self
+    }
+
+    /// Also refuse whatever matches the glob `pattern`.
+    ///
+    /// A pattern matches a file or directory by name, as `*.bak` or `.git`
+    /// do, or by its path from the content dir, as `drafts/*` does.
+    /// Whatever is under a refused directory is refused too.
+    pub fn with_hidden_pattern(mut self, pattern: &str) -> Result<Self, StaticHandlerError> {
+        let compiled = Pattern::new(pattern).map_err(|source| StaticHandlerError::Pattern {
+            pattern: pattern.into(),
+            source,
+        })?;
+        self.hidden.push(compiled);
+        Ok(self)
+    }
+
+    /// Whether `relative`, or a directory it's in, is hidden.
+    fn is_hidden(&self, relative: &Path) -> bool {
+        const OPTIONS: MatchOptions = MatchOptions {
+            case_sensitive: true,
+            require_literal_separator: true,
+            require_literal_leading_dot: false,
+        };
+        relative
+            .ancestors()
+            .filter(|path| !path.as_os_str().is_empty())
+            .any(|path| {
+                let name = path.file_name().and_then(OsStr::to_str).unwrap_or_default();
+                (self.hide_dotfiles && name.starts_with('.'))
+                    || self.hidden.iter().any(|pattern| {
+                        pattern.matches(name) || pattern.matches_path_with(path, OPTIONS)
+                    })
             })
-            .filter(|p| p.starts_with(&*self.prefix))
-            .map(|p| p.get(self.prefix.len()..).unwrap_or_default())
-            .map(|p| self.path.join(p))
-            .filter(|p| p.starts_with(&self.path))
-            .map(|p| {
-                if p.is_dir() {
-                    {
-                        let path = p.join("index.gemini");
-                        if !path.exists() {
-                            p.join("index.gmi")
-                        } else {
-                            path
-                        }
+    }
+
+    /// Where `relative` leads, if it exists and we may serve it.
+    ///
+    /// Anything refused is indistinguishable from a missing file.
+    fn resolve(&self, relative: &Path) -> Option<PathBuf> {
+        if self.is_hidden(relative) {
+            debug!("Refusing hidden {relative:?}");
+            return None;
+        }
+        let path = self.path.join(relative);
+        let path = match self.symlinks {
+            Symlinks::Always => path.canonicalize().ok()?,
+            Symlinks::WithinRoot => {
+                let path = path.canonicalize().ok()?;
+                if !path.starts_with(&self.path) {
+                    warn!("Refusing {relative:?}: it leads outside {:?}", self.path);
+                    return None;
+                }
+                path
+            }
+            Symlinks::Never => {
+                let mut path = self.path.clone();
+                for component in relative.components() {
+                    path.push(component);
+                    if path.symlink_metadata().ok()?.is_symlink() {
+                        debug!("Refusing {relative:?}: {path:?} is a link");
+                        return None;
                     }
-                } else {
-                    p
                 }
+                path
+            }
+        };
+        // A link may lead somewhere hidden under another name.
+        match path.strip_prefix(&self.path) {
+            Ok(target) if self.is_hidden(target) => None,
+            _ => Some(path),
+        }
+    }
+
+    /// The type of the file at `path`.
+    ///
+    /// Gemtext gets a language from, in order of preference, the file's
+    /// name, the nearest [`LANG_FILE`], or the mount. Text which isn't
+    /// UTF-8 gets the fallback charset.
+    fn mime(&self, path: &Path) -> Mime {
+        let mut mime = self.mime_types.guess(path);
+        if mime.essence() == "text/gemini" && mime.param("lang").is_none() {
+            let lang = mime::filename_lang(path)
+                .map(str::to_owned)
+                .or_else(|| self.directory_lang(path))
+                .or_else(|| self.lang.clone());
+            if let Some(lang) = lang {
+                mime = mime.with_param("lang", lang);
+            }
+        }
+        if mime.is_text() && mime.param("charset").is_none() && !mime::is_utf8(path) {
+            mime = mime.with_param("charset", &self.fallback_charset);
+        }
+        mime
+    }
+
+    /// Serve the file at `path`.
+    fn file(&self, path: &Path) -> Response {
+        let mime = self.mime(path);
+        debug!(
+            "Static handler for '{:?}' is looking for '{path:?}' of mime type '{mime}' on disk",
+            self.prefix
+        );
+        File::open(path)
+            .map(|file| {
+                Response::Disk(FileResponse {
+                    status: Success::Generic,
+                    mime,
+                    file,
+                })
             })
-            .map(|p| (mime_type(&p), p))
-            .inspect(|(mime, p)| {
-                     debug!(
-                    "Static handler for '{:?}' is looking for '{p:?}' of mime type '{mime}' on disk",
-                    self.prefix
-                );
+            .unwrap_or_else(|_| not_found())
+    }
+
+    /// Serve the directory at `dir`, found at `relative`, by its index or
+    /// a listing if we may.
+    fn directory(&self, dir: &Path, relative: &Path, url: &Url) -> Response {
+        if let Some(index) = INDEX_FILES
+            .iter()
+            .filter_map(|name| self.resolve(&relative.join(name)))
+            .find(|index| index.is_file())
+        {
+            return self.file(&index);
+        }
+        let Some(autoindex) = &self.autoindex else {
+            return not_found();
+        };
+        let visible = |name: &str| self.resolve(&relative.join(name)).is_some();
+        match autoindex.render(dir, url.path(), visible) {
+            Ok(page) => Response::Fixed(SuccessResponse {
+                status: Success::Generic,
+                mime: "text/gemini".into(),
+                body: page.into(),
+            }),
+            Err(e) => {
+                warn!("Failed to list {dir:?}: {e}");
+                not_found()
             }
-            )
-            .map(|(mime, p)| {
-                File::open(p)
-                    .map(|file| {
-                        Response::Disk(FileResponse {
-                            status: Success::Generic,
-                            mime,
-                            file,
-                        })
-                    })
-                    .unwrap_or_else(|_| {
-                        // TODO ergonomics
-                        Response::Err(ErrResponse::from_status(Status::PermanentFailure(
-                            crate::status::PermanentFailure::NotFound,
-                        )))
-                    })
+        }
+    }
+
+    /// The language given by the nearest [`LANG_FILE`] above `path` and
+    /// within our directory, skipping any which don't hold a language tag.
+    ///
+    /// This looks for a file in each directory on every request for
+    /// gemtext, so that changes to them are seen without a restart.
+    fn directory_lang(&self, path: &Path) -> Option<String> {
+        path.ancestors()
+            .skip(1)
+            .take_while(|dir| dir.starts_with(&self.path))
+            .find_map(|dir| {
+                let file = dir.join(LANG_FILE);
+                let lang = std::fs::read_to_string(&file).ok()?.trim().to_owned();
+                if !mime::is_lang_tag(&lang) {
+                    warn!("Ignoring {file:?}, which doesn't hold a language tag");
+                    return None;
+                }
+                Some(lang)
             })
     }
 }
 
+impl Handler for StaticHandler {
+    fn handle_request(&self, request: &Request) -> Option<Response> {
+        let url = request.url();
+        if url.query().is_some() {
+            return None;
+        }
+        debug!(
+            "Static handler for '{:?}' is considering request for '{}'",
+            self.prefix,
+            url.path()
+        );
+        if format!("{}/", url.path()) == *self.prefix {
+            return Some(add_slash(url));
+        }
+        let relative = match decode_path(url.path().strip_prefix(&*self.prefix)?) {
+            Ok(relative) => relative,
+            Err(e) => {
+                warn!("Refusing request for '{}': {e}", url.path());
+                return Some(bad_request());
+            }
+        };
+        let Some(path) = self.resolve(&relative) else {
+            return Some(not_found());
+        };
+        Some(if !path.is_dir() {
+            self.file(&path)
+        } else if !url.path().ends_with('/') {
+            // Otherwise relative links would resolve against the parent.
+            add_slash(url)
+        } else {
+            self.directory(&path, &relative, url)
+        })
+    }
+}
+
 #[cfg(test)]
 mod test_static_handler {
     use super::*;
+    use crate::{autoindex::AutoIndex, testing::get};
     use anyhow::Result;
     use rstest::rstest;
     use tempfile::TempDir;
@@ -172,7 +462,7 @@ mod test_static_handler {
         let _ = path.parent().map(std::fs::create_dir_all);
         std::fs::write(&path, "hello world")?;
 
-        let mut handler = StaticHandler::new(dir.path(), prefix)?;
+        let handler = StaticHandler::new(dir.path(), prefix)?;
 
         let req: Request = format!("gemini://example.com/static/{target}\r\n").parse()?;
         let resp = handler.handle_request(&req).expect("handled");
@@ -193,7 +483,7 @@ mod test_static_handler {
         let path = dir.path().join(path);
         std::fs::write(&path, "hello world")?;
 
-        let mut handler = StaticHandler::new(dir.path(), "static")?;
+        let handler = StaticHandler::new(dir.path(), "static")?;
 
         let req: Request = "gemini://example.com/static/\r\n".parse()?;
         let resp = handler.handle_request(&req).expect("handled");
@@ -206,10 +496,234 @@ mod test_static_handler {
         Ok(())
     }
 
+    #[rstest]
+    #[case::subdirectory("static/posts")]
+    #[case::mount("static")]
+    fn redirects_directories_to_a_trailing_slash(#[case] path: &str) -> Result<()> {
+        let dir = TempDir::new()?;
+        std::fs::create_dir(dir.path().join("posts"))?;
+        std::fs::write(dir.path().join("posts/index.gmi"), "hello world")?;
+        std::fs::write(dir.path().join("index.gmi"), "hello world")?;
+        let handler = StaticHandler::new(dir.path(), "static")?;
+
+        let redirect = get(&handler, &format!("gemini://example.com/{path}"))?.expect("handled");
+        let index = get(&handler, &format!("gemini://example.com/{path}/"))?.expect("handled");
+
+        assert_eq!(redirect, format!("31 gemini://example.com/{path}/\r\n"));
+        assert_eq!(index, "20 text/gemini\r\nhello world");
+        Ok(())
+    }
+
+    #[test]
+    fn serves_files_with_their_mime_type() -> Result<()> {
+        let dir = TempDir::new()?;
+        std::fs::write(dir.path().join("photo.png"), "not really")?;
+        std::fs::write(dir.path().join("notes.log"), "hello world")?;
+        let handler = StaticHandler::new(dir.path(), "/")?
+            .with_mime_types(MimeTypes::default().with_override("log", "text/plain"));
+
+        for (name, mime) in [("photo.png", "image/png"), ("notes.log", "text/plain")] {
+            let req: Request = format!("gemini://example.com/{name}\r\n").parse()?;
+            let mut buffer = Vec::new();
+            handler
+                .handle_request(&req)
+                .expect("handled")
+                .send(&mut buffer)?;
+            assert!(String::try_from(buffer)?.starts_with(&format!("20 {mime}\r\n")));
+        }
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::mount("notes.gmi", "text/gemini; lang=en")]
+    #[case::directory("de/notes.gmi", "text/gemini; lang=de")]
+    #[case::nested_directory("de/posts/notes.gmi", "text/gemini; lang=de")]
+    #[case::filename("de/notes.fr.gmi", "text/gemini; lang=fr")]
+    #[case::not_gemtext("de/notes.txt", "text/plain")]
+    #[case::latin1("old.txt", "text/plain; charset=iso-8859-1")]
+    fn declares_language_and_charset(#[case] target: &str, #[case] mime: &str) -> Result<()> {
+        let dir = TempDir::new()?;
+        std::fs::create_dir_all(dir.path().join("de/posts"))?;
+        std::fs::write(dir.path().join("de").join(LANG_FILE), "de\n")?;
+        for name in [
+            "notes.gmi",
+            "de/notes.gmi",
+            "de/posts/notes.gmi",
+            "de/notes.fr.gmi",
+            "de/notes.txt",
+        ] {
+            std::fs::write(dir.path().join(name), "Grüße")?;
+        }
+        std::fs::write(dir.path().join("old.txt"), b"Gr\xfc\xdfe")?;
+        let handler = StaticHandler::new(dir.path(), "/")?.with_lang(Some("en".into()));
+
+        let req: Request = format!("gemini://example.com/{target}\r\n").parse()?;
+        let mut buffer = Vec::new();
+        handler
+            .handle_request(&req)
+            .expect("handled")
+            .send(&mut buffer)?;
+
+        let buffer = String::from_utf8_lossy(&buffer);
+        assert_eq!(
+            buffer.split_once("\r\n").map(|(header, _)| header),
+            Some(&*format!("20 {mime}"))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn languages_and_charsets_must_be_well_formed() -> Result<()> {
+        let dir = TempDir::new()?;
+        std::fs::create_dir(dir.path().join("de"))?;
+        std::fs::write(dir.path().join(LANG_FILE), "en")?;
+        std::fs::write(
+            dir.path().join("de").join(LANG_FILE),
+            "de\r\n20 text/gemini",
+        )?;
+        std::fs::write(dir.path().join("de/notes.gmi"), "Grüße")?;
+        let handler = StaticHandler::new(dir.path(), "/")?
+            .with_lang(Some("fr; charset=x".into()))
+            .with_fallback_charset("x\r\n");
+
+        assert_eq!(
+            get(&handler, "gemini://example.com/de/notes.gmi")?
+                .expect("handled")
+                .split_once("\r\n")
+                .map(|(header, _)| header.to_owned()),
+            Some("20 text/gemini; lang=en".into())
+        );
+        assert_eq!(handler.lang, None);
+        assert_eq!(handler.fallback_charset, DEFAULT_FALLBACK_CHARSET);
+        Ok(())
+    }
+
+    #[test]
+    fn lists_directories_without_an_index_if_asked() -> Result<()> {
+        let dir = TempDir::new()?;
+        std::fs::create_dir(dir.path().join("posts"))?;
+        std::fs::write(dir.path().join("posts/hello.gmi"), "hello")?;
+        let handler = StaticHandler::new(dir.path(), "static")?;
+
+        assert_eq!(
+            get(&handler, "gemini://example.com/static/posts/")?.expect("handled"),
+            "51 PermanentFailure(NotFound)\r\n"
+        );
+
+        let handler = handler.with_autoindex(Some(AutoIndex::default()));
+
+        assert_eq!(
+            get(&handler, "gemini://example.com/static/posts/")?.expect("handled"),
+            "20 text/gemini\r\n# Index of /static/posts/\n\n=> ../ ..\n=> hello.gmi hello.gmi\n"
+        );
+        assert_eq!(
+            get(&handler, "gemini://example.com/static/posts")?.expect("handled"),
+            "31 gemini://example.com/static/posts/\r\n"
+        );
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::dotfile("static/.env", true)]
+    #[case::dot_directory("static/.git/config", true)]
+    #[case::name_pattern("static/posts/hello.gmi.bak", false)]
+    #[case::path_pattern("static/drafts/next.gmi", false)]
+    fn hidden_files_look_missing(#[case] path: &str, #[case] dotfile: bool) -> Result<()> {
+        let dir = TempDir::new()?;
+        for file in [
+            ".env",
+            ".git/config",
+            "posts/hello.gmi.bak",
+            "drafts/next.gmi",
+        ] {
+            let file = dir.path().join(file);
+            std::fs::create_dir_all(file.parent().expect("parent"))?;
+            std::fs::write(file, "hello")?;
+        }
+        let handler = StaticHandler::new(dir.path(), "static")?
+            .with_hidden_pattern("*.bak")?
+            .with_hidden_pattern("drafts/*")?;
+
+        assert_eq!(
+            get(&handler, &format!("gemini://example.com/{path}"))?.expect("handled"),
+            get(&handler, "gemini://example.com/static/missing.gmi")?.expect("handled")
+        );
+
+        let handler = handler.with_hidden_dotfiles(false);
+        let shown = get(&handler, &format!("gemini://example.com/{path}"))?.expect("handled");
+        assert_eq!(shown.starts_with("20 "), dotfile, "{shown}");
+        Ok(())
+    }
+
+    #[test]
+    fn hidden_files_are_not_listed() -> Result<()> {
+        let dir = TempDir::new()?;
+        std::fs::write(dir.path().join(".env"), "")?;
+        std::fs::write(dir.path().join("hello.gmi"), "")?;
+        std::fs::write(dir.path().join("hello.gmi.bak"), "")?;
+        let handler = StaticHandler::new(dir.path(), "/")?
+            .with_autoindex(Some(AutoIndex::default()))
+            .with_hidden_pattern("*.bak")?;
+
+        assert_eq!(
+            get(&handler, "gemini://example.com/")?.expect("handled"),
+            "20 text/gemini\r\n# Index of /\n\n=> hello.gmi hello.gmi\n"
+        );
+        let handler = handler.with_hidden_dotfiles(false);
+        assert_eq!(
+            get(&handler, "gemini://example.com/")?.expect("handled"),
+            "20 text/gemini\r\n# Index of /\n\n=> .env .env\n=> hello.gmi hello.gmi\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_patterns_are_refused() -> Result<()> {
+        let dir = TempDir::new()?;
+
+        let handler = StaticHandler::new(dir.path(), "/")?.with_hidden_pattern("[*");
+
+        assert!(matches!(handler, Err(StaticHandlerError::Pattern { .. })));
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[rstest]
+    #[case::never(Symlinks::Never, false, false)]
+    #[case::within_root(Symlinks::WithinRoot, true, false)]
+    #[case::always(Symlinks::Always, true, true)]
+    fn symlinks_are_followed_as_configured(
+        #[case] symlinks: Symlinks,
+        #[case] inside: bool,
+        #[case] outside: bool,
+    ) -> Result<()> {
+        use std::os::unix::fs::symlink;
+
+        let dir = TempDir::new()?;
+        let root = dir.path().join("public");
+        std::fs::create_dir_all(root.join("posts"))?;
+        std::fs::write(root.join("posts/hello.gmi"), "hello")?;
+        std::fs::write(dir.path().join("secret.gmi"), "secret")?;
+        symlink(root.join("posts"), root.join("latest"))?;
+        symlink(dir.path().join("secret.gmi"), root.join("secret.gmi"))?;
+        let handler = StaticHandler::new(&root, "/")?.with_symlinks(symlinks);
+
+        let served = |path| -> Result<bool> {
+            Ok(get(&handler, &format!("gemini://example.com/{path}"))?
+                .expect("handled")
+                .starts_with("20 "))
+        };
+
+        assert!(served("posts/hello.gmi")?);
+        assert_eq!(served("latest/hello.gmi")?, inside);
+        assert_eq!(served("secret.gmi")?, outside);
+        Ok(())
+    }
+
     #[test]
     fn rejects_requests_from_outside_its_content_dir() -> Result<()> {
         let dir = TempDir::new()?;
-        let mut handler = StaticHandler::new(dir.path(), "static")?;
+        let handler = StaticHandler::new(dir.path(), "static")?;
 
         // let req: Request = "gemini://example.co.uk/static/../../passwd\r\n".parse()?;
         let req: Request =
@@ -219,14 +733,14 @@ mod test_static_handler {
         let mut buffer = Vec::new();
         resp.send(&mut buffer)?;
         let buffer = String::try_from(buffer)?;
-        assert_eq!(buffer, "51 PermanentFailure(NotFound)\r\n");
+        assert_eq!(buffer, "59 PermanentFailure(BadRequest)\r\n");
         Ok(())
     }
 
     #[test]
     fn ignores_requests_not_starting_with_its_prefix() -> Result<()> {
         let dir = TempDir::new()?;
-        let mut handler = StaticHandler::new(dir.path(), "static")?;
+        let handler = StaticHandler::new(dir.path(), "static")?;
 
         let req: Request = "gemini://example.co.uk/dynamic/../../passwd\r\n".parse()?;
 
@@ -234,3 +748,101 @@ mod test_static_handler {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test_traversal {
+    use super::*;
+    use crate::testing::get;
+    use anyhow::Result;
+    use rstest::rstest;
+    use tempfile::TempDir;
+
+    #[rstest]
+    #[case::plain("posts/hello.gmi", "posts/hello.gmi")]
+    #[case::space("a%20note.txt", "a note.txt")]
+    #[case::non_ascii("caf%C3%A9.gmi", "café.gmi")]
+    #[case::empty_and_dot_segments("posts//./hello.gmi", "posts/hello.gmi")]
+    #[case::trailing_slash("posts/", "posts")]
+    #[case::dots_in_names("..hidden/a..b", "..hidden/a..b")]
+    fn paths_are_decoded_segment_by_segment(#[case] path: &str, #[case] expected: &str) {
+        assert_eq!(decode_path(path), Ok(PathBuf::from(expected)));
+    }
+
+    #[rstest]
+    #[case::parent("posts/../../etc/passwd", BadPath::Parent)]
+    #[case::encoded_parent("%2e%2E/etc/passwd", BadPath::Parent)]
+    #[case::encoded_slash("..%2Fetc%2Fpasswd", BadPath::EncodedSeparator)]
+    #[case::encoded_slash_inside("posts%2Fhello.gmi", BadPath::EncodedSeparator)]
+    #[case::nul("hello.gmi%00.txt", BadPath::Nul)]
+    #[case::invalid_utf8("caf%E9.gmi", BadPath::NotUtf8)]
+    fn dangerous_paths_are_refused(#[case] path: &str, #[case] error: BadPath) {
+        assert_eq!(decode_path(path), Err(error));
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn encoded_backslashes_are_refused() {
+        assert_eq!(
+            decode_path("..%5C..%5Cwindows"),
+            Err(BadPath::EncodedSeparator)
+        );
+    }
+
+    /// A content dir at `public`, with a secret beside it.
+    fn site() -> Result<(TempDir, StaticHandler)> {
+        let dir = TempDir::new()?;
+        std::fs::create_dir(dir.path().join("public"))?;
+        std::fs::write(dir.path().join("secret.txt"), "secret")?;
+        std::fs::write(dir.path().join("public/café.gmi"), "bonjour")?;
+        std::fs::write(dir.path().join("public/a note.txt"), "hello")?;
+        let handler = StaticHandler::new(dir.path().join("public"), "static")?;
+        Ok((dir, handler))
+    }
+
+    #[rstest]
+    #[case::non_ascii("caf%C3%A9.gmi", "20 text/gemini\r\nbonjour")]
+    #[case::space("a%20note.txt", "20 text/plain\r\nhello")]
+    fn encoded_names_are_served(#[case] name: &str, #[case] expected: &str) -> Result<()> {
+        let (_dir, handler) = site()?;
+
+        let output = get(&handler, &format!("gemini://example.com/static/{name}"))?;
+
+        assert_eq!(output.as_deref(), Some(expected));
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::dot_dot("static/../secret.txt")]
+    #[case::encoded_dot_dot("static/%2e%2e/secret.txt")]
+    #[case::encoded_slash("static/..%2Fsecret.txt")]
+    #[case::double_encoded("static/%252e%252e/secret.txt")]
+    #[case::nul("static/%00/../secret.txt")]
+    fn nothing_outside_the_content_dir_is_served(#[case] path: &str) -> Result<()> {
+        let (_dir, handler) = site()?;
+
+        let output = get(&handler, &format!("gemini://example.com/{path}"))?;
+
+        assert!(
+            output
+                .as_deref()
+                .is_none_or(|output| !output.starts_with("20 ")),
+            "{output:?}"
+        );
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn links_leading_outside_the_content_dir_are_not_followed() -> Result<()> {
+        let (dir, handler) = site()?;
+        std::os::unix::fs::symlink(
+            dir.path().join("secret.txt"),
+            dir.path().join("public/secret.txt"),
+        )?;
+
+        let output = get(&handler, "gemini://example.com/static/secret.txt")?;
+
+        assert_eq!(output.as_deref(), Some("51 PermanentFailure(NotFound)\r\n"));
+        Ok(())
+    }
+}
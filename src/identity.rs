@@ -0,0 +1,96 @@
+use std::path::{Path, PathBuf};
+
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, pem, pem::PemObject};
+
+/// A certificate chain and the private key for its leaf.
+#[derive(Debug)]
+pub struct Identity {
+    /// The leaf certificate first, then any intermediates.
+    pub chain: Vec<CertificateDer<'static>>,
+    pub key: PrivateKeyDer<'static>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum IdentityError {
+    #[error("Failed to read certificates from `{path}`: {source}")]
+    Certificate { path: PathBuf, source: pem::Error },
+    #[error("No certificates found in `{0}`")]
+    NoCertificates(PathBuf),
+    #[error("Failed to read private key from `{path}`: {source}")]
+    PrivateKey { path: PathBuf, source: pem::Error },
+    #[error("Certificate `{certificate}` does not match private key `{private_key}`: {source}")]
+    Mismatch {
+        certificate: PathBuf,
+        private_key: PathBuf,
+        source: rustls::Error,
+    },
+}
+
+impl Identity {
+    /// Load every certificate in `certificate` as a chain, and the first
+    /// PKCS#1, PKCS#8 or SEC1 key in `private_key`.
+    pub fn from_pem_files(certificate: &Path, private_key: &Path) -> Result<Self, IdentityError> {
+        let chain = CertificateDer::pem_file_iter(certificate)
+            .and_then(Iterator::collect::<Result<Vec<_>, _>>)
+            .map_err(|source| IdentityError::Certificate {
+                path: certificate.into(),
+                source,
+            })?;
+        if chain.is_empty() {
+            return Err(IdentityError::NoCertificates(certificate.into()));
+        }
+        let key = PrivateKeyDer::from_pem_file(private_key).map_err(|source| {
+            IdentityError::PrivateKey {
+                path: private_key.into(),
+                source,
+            }
+        })?;
+        Ok(Self { chain, key })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::testdata;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case::pkcs1("rsa.crt", "rsa-pkcs1.key")]
+    #[case::pkcs8("ec.crt", "ec-pkcs8.key")]
+    #[case::sec1("ec.crt", "ec-sec1.key")]
+    fn keys_are_loaded_in_any_format(#[case] certificate: &str, #[case] private_key: &str) {
+        let identity =
+            Identity::from_pem_files(&testdata(certificate), &testdata(private_key)).unwrap();
+        assert_eq!(identity.chain.len(), 1);
+    }
+
+    #[test]
+    fn every_certificate_in_the_file_is_loaded() {
+        let identity =
+            Identity::from_pem_files(&testdata("chain.crt"), &testdata("ec-sec1.key")).unwrap();
+        assert_eq!(identity.chain.len(), 2);
+    }
+
+    #[test]
+    fn a_missing_certificate_names_the_file() {
+        let err = Identity::from_pem_files(&testdata("missing.crt"), &testdata("ec-sec1.key"))
+            .unwrap_err();
+        assert!(matches!(err, IdentityError::Certificate { .. }));
+        assert!(err.to_string().contains("missing.crt"));
+    }
+
+    #[test]
+    fn a_file_without_certificates_is_rejected() {
+        let err = Identity::from_pem_files(&testdata("ec-sec1.key"), &testdata("ec-sec1.key"))
+            .unwrap_err();
+        assert!(matches!(err, IdentityError::NoCertificates(_)));
+    }
+
+    #[test]
+    fn a_file_without_a_key_names_the_file() {
+        let err = Identity::from_pem_files(&testdata("ec.crt"), &testdata("ec.crt")).unwrap_err();
+        assert!(matches!(err, IdentityError::PrivateKey { .. }));
+        assert!(err.to_string().contains("ec.crt"));
+    }
+}
@@ -0,0 +1,78 @@
+//! A small Gemini server, for embedding or as the `inimeg` binary.
+//!
+//! A [`Server`] serves one or more [`VirtualHost`]s, each with its own
+//! certificate and a list of [`Handler`]s consulted in order until one answers.
+//!
+//! ```no_run
+//! use inimeg::{Handler, Request, Response, Server, StaticHandler, VirtualHost};
+//! use inimeg::response::SuccessResponse;
+//! use inimeg::status::Success;
+//!
+//! struct Hello;
+//!
+//! impl Handler for Hello {
+//!     fn handle_request(&self, request: &Request) -> Option<Response> {
+//!         (request.url().path() == "/hello").then(|| {
+//!             Response::Fixed(SuccessResponse {
+//!                 status: Success::Generic,
+//!                 mime: "text/gemini".into(),
+//!                 body: "# Hello\n".into(),
+//!             })
+//!         })
+//!     }
+//! }
+//!
+//! # fn main() -> anyhow::Result<()> {
+//! let mut host = VirtualHost::from_pem_files(["example.org"], "cert.pem".as_ref(), "key.pem".as_ref())?;
+//! host.add_handler(Box::new(Hello));
+//! host.add_handler(Box::new(StaticHandler::new("/srv/gemini", "/")?));
+//!
+//! let server = Server::builder().host(host).build()?;
+//! server.handle_signals()?;
+//! server.run()
+//! # }
+//! ```
+
+pub mod access_log;
+mod appender;
+pub mod autoindex;
+pub mod capture;
+pub mod cgi;
+mod client_cert;
+pub mod gencert;
+pub mod handler;
+pub mod identity;
+pub mod middleware;
+pub mod mime;
+mod net;
+mod pool;
+pub mod proxy;
+pub mod redirect;
+pub mod request;
+pub mod response;
+pub mod router;
+pub mod scgi;
+pub mod server;
+pub mod status;
+#[cfg(test)]
+mod testing;
+mod timeout;
+pub mod vhost;
+
+pub use access_log::AccessLog;
+pub use cgi::CgiHandler;
+pub use client_cert::ClientCertificate;
+pub use handler::{Handler, StaticHandler};
+pub use middleware::{Layered, Middleware};
+pub use mime::{Mime, MimeTypes};
+pub use pool::PoolConfig;
+pub use proxy::ProxyHandler;
+pub use redirect::RedirectHandler;
+pub use request::Request;
+pub use response::Response;
+pub use router::{Route, Router};
+pub use scgi::ScgiHandler;
+pub use server::{Server, ServerBuilder};
+pub use status::Status;
+pub use timeout::Timeouts;
+pub use vhost::VirtualHost;
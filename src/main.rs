@@ -1,37 +1,55 @@
-use anyhow::{Context, Result};
+use std::{fs::File, io::BufReader};
+
+use anyhow::{Context, Result, bail};
 use clap::Parser;
 use cli::Cli;
+use config::Config;
+use inimeg::{capture::Replayer, gencert::SelfSigned};
+use log::info;
 
 mod cli;
-mod handler;
-#[allow(dead_code)]
-mod request;
-#[allow(dead_code)]
-mod response;
-mod server;
-mod status;
+mod config;
 
 fn main() -> Result<()> {
     let cli = cli::Cli::parse();
     pretty_env_logger::init();
     match cli {
-        Cli::Serve(config) => {
-            let mut server = server::Server::try_from(&config)?;
-            if let Some(paths) = config.static_dirs {
-                for path in paths {
-                    let handler = handler::StaticHandler::new(
-                        path.canonicalize()?,
-                        path.file_stem().context("File stem")?.to_string_lossy(),
-                    )?;
-                    server.add_handler(Box::new(handler));
-                }
-            }
-            if let Some(path) = config.root_dir {
-                let handler = handler::StaticHandler::new(path.canonicalize()?, "/")?;
-                server.add_handler(Box::new(handler));
+        Cli::Serve(args) => {
+            let config = match &args.config {
+                Some(path) => Config::load(path)?,
+                None => Config::default(),
             }
+            .merge_cli(&args)?;
+            let server = config.server()?;
+            server.handle_signals()?;
+            info!("Listening on {}", server.local_addr()?);
             server.run()?;
         }
+        Cli::Gencert(config) => {
+            SelfSigned::generate(&config.hostname, config.days)?.write(
+                &config.certificate,
+                &config.private_key,
+                config.force,
+            )?;
+        }
+        Cli::Replay(args) => {
+            let hosts = Config::load(&args.config)?.build_hosts_without_certificates()?;
+            let replayer = Replayer::new(hosts)?;
+            let capture =
+                File::open(&args.capture).with_context(|| format!("Capture {:?}", args.capture))?;
+            let report = replayer.replay_all(BufReader::new(capture))?;
+            for difference in &report.differences {
+                println!("{difference}");
+            }
+            if !report.differences.is_empty() {
+                bail!(
+                    "{} of {} requests were answered differently",
+                    report.differences.len(),
+                    report.replayed
+                );
+            }
+            println!("All {} requests were answered as before", report.replayed);
+        }
     }
     Ok(())
 }
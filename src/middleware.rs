@@ -0,0 +1,248 @@
+use crate::{handler::Handler, request::Request, response::Response};
+
+/// Something which sits around a [`Handler`], seeing each request before it
+/// does and each response after.
+///
+/// Closures taking the request and the next handler are middleware too.
+pub trait Middleware: Send + Sync {
+    /// Answer `request`, usually by asking `next` and perhaps changing what
+    /// it says. Answering without asking `next` short-circuits it, and
+    /// `next` may be asked about a different request altogether, say one
+    /// made with [`Request::with_url`].
+    fn handle(&self, request: &Request, next: &dyn Handler) -> Option<Response>;
+}
+
+impl<F> Middleware for F
+where
+    F: Fn(&Request, &dyn Handler) -> Option<Response> + Send + Sync,
+{
+    fn handle(&self, request: &Request, next: &dyn Handler) -> Option<Response> {
+        self(request, next)
+    }
+}
+
+/// A handler wrapped in layers of middleware.
+///
+/// ```
+/// use inimeg::{Layered, Request, Response, StaticHandler};
+/// use inimeg::handler::Handler;
+/// use inimeg::response::ErrResponse;
+/// use inimeg::status::{CertificateRequired, Status};
+///
+/// # fn main() -> anyhow::Result<()> {
+/// # let dir = tempfile::TempDir::new()?;
+/// let private = Layered::new(Box::new(StaticHandler::new(dir.path(), "/private/")?))
+///     .with_layer(|request: &Request, next: &dyn Handler| {
+///         match request.client_certificate() {
+///             Some(_) => next.handle_request(request),
+///             None => Some(Response::Err(ErrResponse::from_status(
+///                 Status::CertificateRequired(CertificateRequired::Generic),
+///             ))),
+///         }
+///     });
+/// # Ok(())
+/// # }
+/// ```
+pub struct Layered {
+    /// Innermost first.
+    layers: Vec<Box<dyn Middleware>>,
+    handler: Box<dyn Handler>,
+}
+
+impl Layered {
+    pub fn new(handler: Box<dyn Handler>) -> Self {
+        Self {
+            layers: Vec::new(),
+            handler,
+        }
+    }
+
+    /// Wrap everything so far in `middleware`, which will see requests
+    /// first.
+    pub fn with_layer(mut self, middleware: impl Middleware + 'static) -> Self {
+        self.layers.push(Box::new(middleware));
+        self
+    }
+}
+
+impl std::fmt::Debug for Layered {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Layered")
+            .field("layers", &self.layers.len())
+            .finish()
+    }
+}
+
+/// The layers inside one, and the handler at the bottom.
+struct Next<'a> {
+    layers: &'a [Box<dyn Middleware>],
+    handler: &'a dyn Handler,
+}
+
+impl Handler for Next<'_> {
+    fn handle_request(&self, request: &Request) -> Option<Response> {
+        match self.layers.split_last() {
+            Some((outermost, layers)) => outermost.handle(
+                request,
+                &Next {
+                    layers,
+                    handler: self.handler,
+                },
+            ),
+            None => self.handler.handle_request(request),
+        }
+    }
+}
+
+impl Handler for Layered {
+    fn handle_request(&self, request: &Request) -> Option<Response> {
+        Next {
+            layers: &self.layers,
+            handler: &*self.handler,
+        }
+        .handle_request(request)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        client_cert::ClientCertificate,
+        response::{ErrResponse, SuccessResponse},
+        status::{PermanentFailure, Status, Success},
+        testing::{get, sent},
+    };
+    use anyhow::Result;
+    use std::sync::{Arc, Mutex};
+
+    /// Answers with the request's path, or 51 under `/missing`.
+    struct Echo;
+
+    impl Handler for Echo {
+        fn handle_request(&self, request: &Request) -> Option<Response> {
+            let path = request.url().path();
+            Some(if path.starts_with("/missing") {
+                Response::Err(ErrResponse::from_status(Status::PermanentFailure(
+                    PermanentFailure::NotFound,
+                )))
+            } else {
+                Response::Fixed(SuccessResponse {
+                    status: Success::Generic,
+                    mime: "text/plain".into(),
+                    body: path.to_owned().into(),
+                })
+            })
+        }
+    }
+
+    #[test]
+    fn middleware_may_answer_instead_of_the_handler() -> Result<()> {
+        let handler =
+            Layered::new(Box::new(Echo)).with_layer(|request: &Request, next: &dyn Handler| {
+                match request.url().query() {
+                    Some(_) => None,
+                    None => next.handle_request(request),
+                }
+            });
+
+        assert_eq!(get(&handler, "gemini://example.com/a?q")?, None);
+        assert_eq!(
+            get(&handler, "gemini://example.com/a")?.as_deref(),
+            Some("20 text/plain\r\n/a")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn middleware_may_rewrite_responses() -> Result<()> {
+        let error_page = |request: &Request, next: &dyn Handler| {
+            next.handle_request(request).map(|response| match response {
+                Response::Err(ErrResponse {
+                    status: Status::PermanentFailure(PermanentFailure::NotFound),
+                    ..
+                }) => Response::Fixed(SuccessResponse {
+                    status: Success::Generic,
+                    mime: "text/gemini".into(),
+                    body: "# Nothing here\n".into(),
+                }),
+                response => response,
+            })
+        };
+        let handler = Layered::new(Box::new(Echo)).with_layer(error_page);
+
+        assert_eq!(
+            get(&handler, "gemini://example.com/missing")?.as_deref(),
+            Some("20 text/gemini\r\n# Nothing here\n")
+        );
+        assert_eq!(
+            get(&handler, "gemini://example.com/found")?.as_deref(),
+            Some("20 text/plain\r\n/found")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn the_last_layer_added_sees_requests_first() -> Result<()> {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let layer = |name: &'static str| {
+            let seen = seen.clone();
+            move |request: &Request, next: &dyn Handler| {
+                seen.lock().expect("lock").push(name);
+                let mut url = request.url().clone();
+                url.set_path(&format!("{}/{name}", url.path().trim_end_matches('/')));
+                next.handle_request(&request.clone().with_url(url))
+            }
+        };
+        let handler = Layered::new(Box::new(Echo))
+            .with_layer(layer("inner"))
+            .with_layer(layer("outer"));
+
+        let output = get(&handler, "gemini://example.com/")?;
+
+        assert_eq!(output.as_deref(), Some("20 text/plain\r\n/outer/inner"));
+        assert_eq!(*seen.lock().expect("lock"), ["outer", "inner"]);
+        Ok(())
+    }
+
+    #[test]
+    fn rewritten_requests_keep_the_client() -> Result<()> {
+        let whoami = |request: &Request, _: &dyn Handler| {
+            let certificate = request.client_certificate()?;
+            Some(Response::Fixed(SuccessResponse {
+                status: Success::Generic,
+                mime: "text/plain".into(),
+                body: format!(
+                    "{} {} {}",
+                    request.url().path(),
+                    request.peer_addr()?,
+                    certificate.fingerprint_hex()
+                )
+                .into(),
+            }))
+        };
+        let rewrite = |request: &Request, next: &dyn Handler| {
+            let url = request.url().join("/elsewhere").ok()?;
+            next.handle_request(&request.clone().with_url(url))
+        };
+        let handler = Layered::new(Box::new(Echo))
+            .with_layer(whoami)
+            .with_layer(rewrite);
+        let certificate = ClientCertificate::new(b"abc".to_vec().into());
+        let request = "gemini://example.com/\r\n"
+            .parse::<Request>()?
+            .with_peer_addr(Some("192.0.2.1:4321".parse()?))
+            .with_client_certificate(Some(certificate.clone()));
+
+        let output = sent(handler.handle_request(&request).expect("handled"))?;
+
+        assert_eq!(
+            output,
+            format!(
+                "20 text/plain\r\n/elsewhere 192.0.2.1:4321 {}",
+                certificate.fingerprint_hex()
+            )
+        );
+        Ok(())
+    }
+}
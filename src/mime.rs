@@ -0,0 +1,415 @@
+use std::{collections::HashMap, fmt, fs::File, io::Read, path::Path};
+
+/// What we call files we know nothing about.
+pub const UNKNOWN: &str = "application/octet-stream";
+
+/// How many bytes of a file we look at when sniffing.
+const SNIFF_LEN: u64 = 512;
+
+/// How many bytes of a text file we check are UTF-8.
+const UTF8_CHECK_LEN: u64 = 64 * 1024;
+
+/// Types by lowercase extension.
+const TYPES: &[(&str, &str)] = &[
+    // Text
+    ("gmi", "text/gemini"),
+    ("gemini", "text/gemini"),
+    ("txt", "text/plain"),
+    ("text", "text/plain"),
+    ("md", "text/markdown"),
+    ("markdown", "text/markdown"),
+    ("org", "text/org"),
+    ("rst", "text/x-rst"),
+    ("tex", "text/x-tex"),
+    ("csv", "text/csv"),
+    ("tsv", "text/tab-separated-values"),
+    ("html", "text/html"),
+    ("htm", "text/html"),
+    ("css", "text/css"),
+    ("js", "text/javascript"),
+    ("ics", "text/calendar"),
+    ("vcf", "text/vcard"),
+    ("diff", "text/x-diff"),
+    ("patch", "text/x-diff"),
+    ("c", "text/x-c"),
+    ("h", "text/x-c"),
+    ("rs", "text/x-rust"),
+    ("py", "text/x-python"),
+    ("sh", "text/x-shellscript"),
+    ("toml", "application/toml"),
+    ("yaml", "application/yaml"),
+    ("yml", "application/yaml"),
+    ("json", "application/json"),
+    ("xml", "application/xml"),
+    ("atom", "application/atom+xml"),
+    ("rss", "application/rss+xml"),
+    // Images
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("webp", "image/webp"),
+    ("avif", "image/avif"),
+    ("svg", "image/svg+xml"),
+    ("bmp", "image/bmp"),
+    ("ico", "image/vnd.microsoft.icon"),
+    ("tif", "image/tiff"),
+    ("tiff", "image/tiff"),
+    // Audio and video
+    ("mp3", "audio/mpeg"),
+    ("ogg", "audio/ogg"),
+    ("oga", "audio/ogg"),
+    ("opus", "audio/opus"),
+    ("flac", "audio/flac"),
+    ("wav", "audio/wav"),
+    ("m4a", "audio/mp4"),
+    ("mid", "audio/midi"),
+    ("midi", "audio/midi"),
+    ("mp4", "video/mp4"),
+    ("m4v", "video/mp4"),
+    ("webm", "video/webm"),
+    ("ogv", "video/ogg"),
+    ("mkv", "video/x-matroska"),
+    ("mov", "video/quicktime"),
+    // Documents
+    ("pdf", "application/pdf"),
+    ("epub", "application/epub+zip"),
+    ("ps", "application/postscript"),
+    ("odt", "application/vnd.oasis.opendocument.text"),
+    ("ods", "application/vnd.oasis.opendocument.spreadsheet"),
+    // Archives
+    ("zip", "application/zip"),
+    ("gz", "application/gzip"),
+    ("tgz", "application/gzip"),
+    ("tar", "application/x-tar"),
+    ("xz", "application/x-xz"),
+    ("bz2", "application/x-bzip2"),
+    ("zst", "application/zstd"),
+    ("7z", "application/x-7z-compressed"),
+    // Fonts and the rest
+    ("woff", "font/woff"),
+    ("woff2", "font/woff2"),
+    ("ttf", "font/ttf"),
+    ("otf", "font/otf"),
+    ("wasm", "application/wasm"),
+    ("asc", "application/pgp-signature"),
+    ("sig", "application/pgp-signature"),
+];
+
+/// Magic numbers, for files without an extension.
+const SIGNATURES: &[(&[u8], &str)] = &[
+    (b"\x89PNG\r\n\x1a\n", "image/png"),
+    (b"\xff\xd8\xff", "image/jpeg"),
+    (b"GIF87a", "image/gif"),
+    (b"GIF89a", "image/gif"),
+    (b"%PDF-", "application/pdf"),
+    (b"OggS", "audio/ogg"),
+    (b"fLaC", "audio/flac"),
+    (b"ID3", "audio/mpeg"),
+    (b"PK\x03\x04", "application/zip"),
+    (b"\x1f\x8b", "application/gzip"),
+    (b"\xfd7zXZ\x00", "application/x-xz"),
+    (b"\x00asm", "application/wasm"),
+];
+
+/// A MIME type and its parameters, as in `text/gemini; lang=de`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mime {
+    essence: String,
+    params: Vec<(String, String)>,
+}
+
+impl Mime {
+    pub fn new(essence: impl Into<String>) -> Self {
+        Self {
+            essence: essence.into().to_lowercase(),
+            params: Vec::new(),
+        }
+    }
+
+    /// The type without its parameters, say `text/gemini`.
+    pub fn essence(&self) -> &str {
+        &self.essence
+    }
+
+    pub fn is_text(&self) -> bool {
+        self.essence.starts_with("text/")
+    }
+
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Set a parameter, replacing any of the same name.
+    pub fn with_param(mut self, name: &str, value: impl Into<String>) -> Self {
+        let name = name.to_lowercase();
+        let value = value.into();
+        match self.params.iter_mut().find(|(key, _)| *key == name) {
+            Some((_, old)) => *old = value,
+            None => self.params.push((name, value)),
+        }
+        self
+    }
+}
+
+impl From<&str> for Mime {
+    /// Parse `type/subtype; name=value; ...`, skipping malformed parameters
+    /// and any with control characters, which have no place in a header.
+    fn from(value: &str) -> Self {
+        let mut parts = value.split(';');
+        let essence = parts.next().unwrap_or_default().trim();
+        parts
+            .filter(|param| !param.contains(char::is_control))
+            .filter_map(|param| param.split_once('='))
+            .fold(Self::new(essence), |mime, (name, value)| {
+                mime.with_param(name.trim(), value.trim())
+            })
+    }
+}
+
+impl From<String> for Mime {
+    fn from(value: String) -> Self {
+        value.as_str().into()
+    }
+}
+
+impl fmt::Display for Mime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.essence)?;
+        for (name, value) in &self.params {
+            write!(f, "; {name}={value}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Decides what type of content a file holds, from its extension or, if it
+/// has none and we're allowed, its first few bytes. Files without an
+/// extension are otherwise plain text if they look like UTF-8.
+#[derive(Debug, Clone, Default)]
+pub struct MimeTypes {
+    /// Types by lowercase extension, consulted before the built in table.
+    overrides: HashMap<String, Mime>,
+    sniff: bool,
+}
+
+impl MimeTypes {
+    /// Serve files ending in `.extension` as `mime`.
+    pub fn with_override(mut self, extension: &str, mime: impl Into<Mime>) -> Self {
+        self.overrides.insert(
+            extension.trim_start_matches('.').to_lowercase(),
+            mime.into(),
+        );
+        self
+    }
+
+    /// Look inside files without an extension to decide what they are.
+    pub fn with_sniffing(mut self, sniff: bool) -> Self {
+        self.sniff = sniff;
+        self
+    }
+
+    pub fn guess(&self, path: &Path) -> Mime {
+        match path.extension().and_then(|s| s.to_str()) {
+            Some(extension) => self.by_extension(&extension.to_lowercase()),
+            None if self.sniff => sniff(path).into(),
+            // Without an extension a file is probably a README or the like,
+            // better shown than downloaded.
+            None if head(path, SNIFF_LEN).is_some_and(|head| looks_like_text(&head)) => {
+                "text/plain".into()
+            }
+            None => UNKNOWN.into(),
+        }
+    }
+
+    fn by_extension(&self, extension: &str) -> Mime {
+        self.overrides.get(extension).cloned().unwrap_or_else(|| {
+            TYPES
+                .iter()
+                .find(|(known, _)| *known == extension)
+                .map_or(UNKNOWN, |(_, mime)| *mime)
+                .into()
+        })
+    }
+}
+
+/// Suffixes which mark a copy of a file rather than its language.
+const COPY_SUFFIXES: &[&str] = &["bak", "new", "old", "orig", "tmp"];
+
+/// The language named by a suffix before the extension, as in
+/// `index.de.gmi`, `index.haw.gmi` or `about.pt-BR.gmi`.
+///
+/// ISO 639 has a code for most short words, so suffixes which are file
+/// extensions themselves (`script.js.gmi`) or mark a copy (`notes.old.gmi`)
+/// are taken to be part of the name.
+pub fn filename_lang(path: &Path) -> Option<&str> {
+    let stem = Path::new(path.file_stem()?);
+    let suffix = stem.extension()?.to_str()?;
+    let lowercase = suffix.to_lowercase();
+    let part_of_name = TYPES.iter().any(|(extension, _)| *extension == lowercase)
+        || COPY_SUFFIXES.contains(&lowercase.as_str());
+    (is_lang_tag(suffix) && !part_of_name).then_some(suffix)
+}
+
+/// Whether `tag` has the shape of a BCP 47 language tag: a two or three
+/// letter language, then any subtags of up to eight letters and digits.
+pub fn is_lang_tag(tag: &str) -> bool {
+    let mut subtags = tag.split('-');
+    subtags.next().is_some_and(|language| {
+        (2..=3).contains(&language.len()) && language.bytes().all(|b| b.is_ascii_alphabetic())
+    }) && subtags.all(|subtag| {
+        (1..=8).contains(&subtag.len()) && subtag.bytes().all(|b| b.is_ascii_alphanumeric())
+    })
+}
+
+/// Whether `name` may be a charset, as registered with IANA.
+pub fn is_charset(name: &str) -> bool {
+    (1..=40).contains(&name.len())
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'+-^_`{}~".contains(&b))
+}
+
+/// Whether the start of the file at `path` is UTF-8, which is what Gemini
+/// assumes of text unless told otherwise.
+///
+/// Files we can't read are given the benefit of the doubt.
+pub fn is_utf8(path: &Path) -> bool {
+    head(path, UTF8_CHECK_LEN).is_none_or(|head| looks_like_utf8(&head))
+}
+
+fn head(path: &Path, len: u64) -> Option<Vec<u8>> {
+    let mut head = Vec::new();
+    File::open(path)
+        .and_then(|file| file.take(len).read_to_end(&mut head))
+        .ok()?;
+    Some(head)
+}
+
+fn looks_like_utf8(head: &[u8]) -> bool {
+    match std::str::from_utf8(head) {
+        Ok(_) => true,
+        // We may have cut a character in half.
+        Err(e) => e.error_len().is_none(),
+    }
+}
+
+/// Guess from a file's magic number, calling it text if it looks like UTF-8.
+fn sniff(path: &Path) -> &'static str {
+    let Some(head) = head(path, SNIFF_LEN) else {
+        return UNKNOWN;
+    };
+    if let Some((_, mime)) = SIGNATURES.iter().find(|(magic, _)| head.starts_with(magic)) {
+        return mime;
+    }
+    if head.starts_with(b"RIFF") && head.get(8..12) == Some(b"WEBP") {
+        return "image/webp";
+    }
+    if looks_like_text(&head) {
+        "text/plain"
+    } else {
+        UNKNOWN
+    }
+}
+
+fn looks_like_text(head: &[u8]) -> bool {
+    looks_like_utf8(head) && !head.contains(&0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use anyhow::Result;
+    use rstest::rstest;
+    use tempfile::TempDir;
+
+    #[rstest]
+    #[case("index.gmi", "text/gemini")]
+    #[case("notes.txt", "text/plain")]
+    #[case("photo.JPG", "image/jpeg")]
+    #[case("talk.pdf", "application/pdf")]
+    #[case("song.ogg", "audio/ogg")]
+    #[case("mystery.xyz", UNKNOWN)]
+    fn types_are_known_by_extension(#[case] name: &str, #[case] mime: &str) {
+        assert_eq!(
+            MimeTypes::default().guess(Path::new(name)).to_string(),
+            mime
+        );
+    }
+
+    #[test]
+    fn overrides_win() {
+        let types = MimeTypes::default()
+            .with_override(".TXT", "text/plain; charset=latin1")
+            .with_override("log", "text/plain");
+
+        assert_eq!(
+            types.guess(Path::new("a.txt")).param("charset"),
+            Some("latin1")
+        );
+        assert_eq!(types.guess(Path::new("b.log")).essence(), "text/plain");
+        assert_eq!(types.guess(Path::new("c.png")).essence(), "image/png");
+    }
+
+    #[rstest]
+    #[case::png(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR", "image/png", UNKNOWN)]
+    #[case::webp(b"RIFF\0\0\0\0WEBPVP8 ", "image/webp", UNKNOWN)]
+    #[case::text(
+        "Plain old text, or ψευδο-text".as_bytes(),
+        "text/plain",
+        "text/plain"
+    )]
+    #[case::binary(b"\x00\x01\x02\x03", UNKNOWN, UNKNOWN)]
+    fn files_without_extensions_may_be_sniffed(
+        #[case] contents: &[u8],
+        #[case] mime: &str,
+        #[case] unsniffed: &str,
+    ) -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("README");
+        std::fs::write(&path, contents)?;
+
+        let sniffed = MimeTypes::default().with_sniffing(true).guess(&path);
+        assert_eq!(sniffed.essence(), mime);
+        assert_eq!(MimeTypes::default().guess(&path).essence(), unsniffed);
+        Ok(())
+    }
+
+    #[test]
+    fn mimes_are_parsed_and_displayed() {
+        let mime = Mime::from("Text/Gemini ;charset=utf-8; junk; x=\r\n20; LANG=de");
+
+        assert_eq!(mime.essence(), "text/gemini");
+        assert_eq!(mime.param("lang"), Some("de"));
+        assert_eq!(
+            mime.with_param("lang", "en").to_string(),
+            "text/gemini; charset=utf-8; lang=en"
+        );
+    }
+
+    #[rstest]
+    #[case("index.de.gmi", Some("de"))]
+    #[case("about.pt-BR.gmi", Some("pt-BR"))]
+    #[case("index.haw.gmi", Some("haw"))]
+    #[case("notes.old.gmi", None)]
+    #[case("script.js.gmi", None)]
+    #[case("notes.v2.gmi", None)]
+    #[case("index.gmi", None)]
+    fn languages_are_read_from_filenames(#[case] name: &str, #[case] lang: Option<&str>) {
+        assert_eq!(filename_lang(Path::new(name)), lang);
+    }
+
+    #[rstest]
+    #[case("en", true)]
+    #[case("zh-Hant-TW", true)]
+    #[case("", false)]
+    #[case("english", false)]
+    #[case("en\r\n20 text/gemini", false)]
+    #[case("en; charset=x", false)]
+    fn language_tags_are_checked(#[case] tag: &str, #[case] valid: bool) {
+        assert_eq!(is_lang_tag(tag), valid);
+    }
+}
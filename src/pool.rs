@@ -0,0 +1,213 @@
+use std::{
+    num::NonZeroUsize,
+    sync::{
+        Arc, Mutex,
+        mpsc::{Receiver, SyncSender, sync_channel},
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use log::{debug, warn};
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// How many connections we handle at once, and how many may wait their turn.
+#[derive(Debug, Clone, Copy)]
+pub struct PoolConfig {
+    /// Number of worker threads.
+    pub workers: NonZeroUsize,
+    /// Number of jobs which may be queued before submission blocks.
+    pub queue_depth: usize,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            workers: NonZeroUsize::new(8).expect("non-zero"),
+            queue_depth: 32,
+        }
+    }
+}
+
+/// A fixed-size pool of worker threads fed from a bounded queue.
+///
+/// When the queue is full [`ThreadPool::execute`] blocks, which stops the
+/// accept loop and leaves further connections in the kernel's backlog.
+pub struct ThreadPool {
+    sender: Option<SyncSender<Job>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("All workers have exited")]
+pub struct PoolClosed;
+
+fn work(id: usize, receiver: Arc<Mutex<Receiver<Job>>>) {
+    loop {
+        // The guard must be dropped before running the job, or we would
+        // serialise every worker behind this one.
+        let job = match receiver.lock() {
+            Ok(receiver) => receiver.recv(),
+            Err(_) => return,
+        };
+        match job {
+            Ok(job) => {
+                if std::panic::catch_unwind(std::panic::AssertUnwindSafe(job)).is_err() {
+                    warn!("Worker {id}: job panicked");
+                }
+            }
+            Err(_) => {
+                debug!("Worker {id}: queue closed, exiting");
+                return;
+            }
+        }
+    }
+}
+
+impl ThreadPool {
+    pub fn new(config: PoolConfig) -> Self {
+        let (sender, receiver) = sync_channel(config.queue_depth);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let workers = (0..config.workers.get())
+            .map(|id| {
+                let receiver = receiver.clone();
+                std::thread::Builder::new()
+                    .name(format!("inimeg-worker-{id}"))
+                    .spawn(move || work(id, receiver))
+                    .expect("spawn worker thread")
+            })
+            .collect();
+        Self {
+            sender: Some(sender),
+            workers,
+        }
+    }
+
+    /// Queue a job, blocking whilst the queue is full.
+    pub fn execute(&self, job: impl FnOnce() + Send + 'static) -> Result<(), PoolClosed> {
+        self.sender
+            .as_ref()
+            .ok_or(PoolClosed)?
+            .send(Box::new(job))
+            .map_err(|_| PoolClosed)
+    }
+
+    /// Close the queue and wait up to `grace` for every queued job to finish.
+    ///
+    /// Returns `false` if some jobs were still running when time ran out.
+    /// Their threads are abandoned, and will die with the process.
+    pub fn shutdown(mut self, grace: Duration) -> bool {
+        drop(self.sender.take());
+        let deadline = Instant::now() + grace;
+        while Instant::now() < deadline {
+            if self.workers.iter().all(|worker| worker.is_finished()) {
+                // Drop will now join them without blocking.
+                return true;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let busy = self.workers.drain(..).filter(|w| !w.is_finished()).count();
+        warn!("{busy} workers still busy after {grace:?}, abandoning them");
+        busy == 0
+    }
+}
+
+impl Drop for ThreadPool {
+    /// Close the queue and wait for every queued job to finish.
+    fn drop(&mut self) {
+        drop(self.sender.take());
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::{Barrier, atomic::AtomicUsize, atomic::Ordering};
+
+    fn config(workers: usize, queue_depth: usize) -> PoolConfig {
+        PoolConfig {
+            workers: NonZeroUsize::new(workers).unwrap(),
+            queue_depth,
+        }
+    }
+
+    #[test]
+    fn jobs_run_concurrently() {
+        let pool = ThreadPool::new(config(4, 4));
+        // Would deadlock if the jobs ran one at a time.
+        let barrier = Arc::new(Barrier::new(4));
+        let (tx, rx) = std::sync::mpsc::channel();
+        for _ in 0..4 {
+            let barrier = barrier.clone();
+            let tx = tx.clone();
+            pool.execute(move || {
+                barrier.wait();
+                tx.send(()).unwrap();
+            })
+            .unwrap();
+        }
+        for _ in 0..4 {
+            rx.recv_timeout(Duration::from_secs(5))
+                .expect("job finished");
+        }
+    }
+
+    #[test]
+    fn dropping_the_pool_drains_the_queue() {
+        let count = Arc::new(AtomicUsize::new(0));
+        {
+            let pool = ThreadPool::new(config(1, 8));
+            for _ in 0..8 {
+                let count = count.clone();
+                pool.execute(move || {
+                    count.fetch_add(1, Ordering::SeqCst);
+                })
+                .unwrap();
+            }
+        }
+        assert_eq!(count.load(Ordering::SeqCst), 8);
+    }
+
+    #[test]
+    fn shutdown_waits_for_running_jobs() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let pool = ThreadPool::new(config(2, 2));
+        for _ in 0..2 {
+            let count = count.clone();
+            pool.execute(move || {
+                std::thread::sleep(Duration::from_millis(50));
+                count.fetch_add(1, Ordering::SeqCst);
+            })
+            .unwrap();
+        }
+        assert!(pool.shutdown(Duration::from_secs(5)));
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn shutdown_gives_up_after_the_grace_period() {
+        let pool = ThreadPool::new(config(1, 1));
+        let (_tx, rx) = std::sync::mpsc::channel::<()>();
+        pool.execute(move || {
+            let _ = rx.recv_timeout(Duration::from_secs(5));
+        })
+        .unwrap();
+
+        let start = Instant::now();
+        assert!(!pool.shutdown(Duration::from_millis(50)));
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn a_panicking_job_does_not_kill_its_worker() {
+        let pool = ThreadPool::new(config(1, 1));
+        pool.execute(|| panic!("oops")).unwrap();
+        let (tx, rx) = std::sync::mpsc::channel();
+        pool.execute(move || tx.send(()).unwrap()).unwrap();
+        rx.recv_timeout(Duration::from_secs(5)).expect("job ran");
+    }
+}
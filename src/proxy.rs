@@ -0,0 +1,309 @@
+use std::{
+    io::{BufReader, Cursor, ErrorKind, Read, Write},
+    net::{IpAddr, TcpStream},
+    sync::Arc,
+    time::Duration,
+};
+
+use log::{debug, warn};
+use rustls::{ClientConfig, ClientConnection, StreamOwned, pki_types::ServerName};
+use url::{Host, Url};
+
+use crate::{
+    cgi::{self, GatewayError},
+    handler::{Handler, Prefix},
+    net::{AcceptAnyCert, connect_tcp},
+    request::Request,
+    response::{ErrResponse, RawResponse, Response},
+    status::{Status, TemporaryFailure},
+};
+
+/// How long the upstream may take to accept a connection, and between
+/// reads or writes thereafter.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Forwards requests under a prefix to another Gemini server.
+///
+/// The upstream's status, meta and body are relayed as they arrive. To
+/// proxy a whole host, give it its own [`VirtualHost`](crate::VirtualHost)
+/// with a proxy under `/`, or restrict the handler with [`Self::for_host`].
+///
+/// Upstreams are usually our own apps on localhost with self-signed
+/// certificates, so any certificate is accepted.
+#[derive(Debug)]
+pub struct ProxyHandler {
+    upstream: Url,
+    /// The prefix required in the url for this handler to match.
+    prefix: Prefix,
+    /// The only host this handler answers for, if any.
+    host: Option<String>,
+    /// Whether to rewrite requests onto the upstream's url.
+    rewrite: bool,
+    timeout: Duration,
+    config: Arc<ClientConfig>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ProxyHandlerError {
+    #[error("Upstream `{0}` is not a gemini:// url")]
+    WrongScheme(Url),
+    #[error("Upstream `{0}` has no host")]
+    NoHost(Url),
+}
+
+impl ProxyHandler {
+    pub fn new(upstream: Url, prefix: impl Into<String>) -> Result<Self, ProxyHandlerError> {
+        if upstream.scheme() != "gemini" {
+            return Err(ProxyHandlerError::WrongScheme(upstream));
+        } else if upstream.host_str().is_none() {
+            return Err(ProxyHandlerError::NoHost(upstream));
+        }
+        let config = ClientConfig::builder()
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(AcceptAnyCert::new()))
+            .with_no_client_auth();
+        Ok(Self {
+            upstream,
+            prefix: Prefix::from(prefix.into()),
+            host: None,
+            rewrite: false,
+            timeout: DEFAULT_TIMEOUT,
+            config: config.into(),
+        })
+    }
+
+    /// Only answer requests for `host`.
+    pub fn for_host(mut self, host: impl Into<String>) -> Self {
+        self.host = Some(host.into().to_lowercase());
+        self
+    }
+
+    /// Rather than forwarding the url as the client sent it, replace the
+    /// prefix with the upstream's url, so that `/app/notes` becomes, say,
+    /// `gemini://localhost:1966/notes`.
+    pub fn with_rewrite(mut self, rewrite: bool) -> Self {
+        self.rewrite = rewrite;
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    fn matches(&self, url: &Url) -> bool {
+        url.path().starts_with(&*self.prefix)
+            && self
+                .host
+                .as_ref()
+                .is_none_or(|host| url.host_str() == Some(host))
+    }
+
+    /// The url to ask the upstream for.
+    fn upstream_url(&self, url: &Url) -> Url {
+        if !self.rewrite {
+            return url.clone();
+        }
+        let mut rewritten = self.upstream.clone();
+        let base = self.upstream.path().trim_end_matches('/');
+        rewritten.set_path(&format!("{base}/{}", &url.path()[self.prefix.len()..]));
+        rewritten.set_query(url.query());
+        rewritten
+    }
+
+    fn connect(&self) -> std::io::Result<StreamOwned<ClientConnection, TcpStream>> {
+        let host = self.upstream.host_str().expect("checked in new");
+        let port = self.upstream.port().unwrap_or(1965);
+        let stream = connect_tcp(&format!("{host}:{port}"), self.timeout)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        let name = match self.upstream.host() {
+            Some(Host::Ipv4(ip)) => ServerName::IpAddress(IpAddr::V4(ip).into()),
+            Some(Host::Ipv6(ip)) => ServerName::IpAddress(IpAddr::V6(ip).into()),
+            _ => ServerName::try_from(host.to_owned()).map_err(std::io::Error::other)?,
+        };
+        let conn =
+            ClientConnection::new(self.config.clone(), name).map_err(std::io::Error::other)?;
+        Ok(StreamOwned::new(conn, stream))
+    }
+
+    fn forward(&self, request: &Request) -> Result<Response, GatewayError> {
+        let url = self.upstream_url(request.url());
+        let mut stream = self.connect().map_err(GatewayError::Connect)?;
+        write!(stream, "{url}\r\n")
+            .and_then(|_| stream.flush())
+            .map_err(GatewayError::Connect)?;
+        let mut reply = BufReader::new(TolerateTruncation(stream));
+        let header = cgi::read_header(&mut reply)?;
+        Ok(Response::Raw(RawResponse {
+            body: Box::new(Cursor::new(header).chain(reply)),
+        }))
+    }
+}
+
+impl Handler for ProxyHandler {
+    fn handle_request(&self, request: &Request) -> Option<Response> {
+        if !self.matches(request.url()) {
+            return None;
+        }
+        debug!("Proxying request to {}", self.upstream);
+        Some(self.forward(request).unwrap_or_else(|e| {
+            warn!("Upstream {} failed: {e}", self.upstream);
+            Response::Err(ErrResponse::from_status(Status::TemporaryFailure(
+                TemporaryFailure::ProxyError,
+            )))
+        }))
+    }
+}
+
+/// Treats a connection closed without a TLS close_notify as finished.
+///
+/// Plenty of Gemini servers just hang up, and clients put up with it.
+struct TolerateTruncation<R>(R);
+
+impl<R: Read> Read for TolerateTruncation<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self.0.read(buf) {
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(0),
+            read => read,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::{get, self_signed};
+    use anyhow::Result;
+    use rstest::rstest;
+    use rustls::{ServerConfig, ServerConnection};
+    use std::{io::BufRead, net::TcpListener, thread::JoinHandle};
+
+    /// An upstream answering one request with `reply`, returning the
+    /// request line it received.
+    fn upstream(reply: &'static str) -> Result<(Url, JoinHandle<Result<String>>)> {
+        upstream_at("127.0.0.1", "localhost", reply)
+    }
+
+    /// An upstream listening on `ip`, known to the proxy as `host`.
+    fn upstream_at(
+        ip: &str,
+        host: &str,
+        reply: &'static str,
+    ) -> Result<(Url, JoinHandle<Result<String>>)> {
+        let listener = TcpListener::bind((ip, 0))?;
+        let url = Url::parse(&format!(
+            "gemini://{host}:{}/",
+            listener.local_addr()?.port()
+        ))?;
+        let (cert, key) = self_signed(&["localhost"]);
+        let config = Arc::new(
+            ServerConfig::builder()
+                .with_no_client_auth()
+                .with_single_cert(vec![cert], key)?,
+        );
+        let served = std::thread::spawn(move || {
+            let (tcp_stream, _) = listener.accept()?;
+            let mut stream = StreamOwned::new(ServerConnection::new(config)?, tcp_stream);
+            let mut request = String::new();
+            BufReader::new(&mut stream).read_line(&mut request)?;
+            stream.write_all(reply.as_bytes())?;
+            stream.conn.send_close_notify();
+            stream.flush()?;
+            Ok(request)
+        });
+        Ok((url, served))
+    }
+
+    #[test]
+    fn responses_are_relayed() -> Result<()> {
+        let (upstream, served) = upstream("20 text/gemini; lang=en\r\n# Hello\n")?;
+        let handler = ProxyHandler::new(upstream, "app")?;
+
+        let output = get(&handler, "gemini://example.com/app/notes?q")?.expect("handled");
+
+        assert_eq!(output, "20 text/gemini; lang=en\r\n# Hello\n");
+        assert_eq!(
+            served.join().expect("upstream")?,
+            "gemini://example.com/app/notes?q\r\n"
+        );
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::ipv4("127.0.0.1", "127.0.0.1")]
+    #[case::ipv6("::1", "[::1]")]
+    fn upstreams_may_be_ip_addresses(#[case] ip: &str, #[case] host: &str) -> Result<()> {
+        let (upstream, served) = upstream_at(ip, host, "20 text/gemini\r\n")?;
+        let handler = ProxyHandler::new(upstream, "app")?;
+
+        let output = get(&handler, "gemini://example.com/app/")?.expect("handled");
+
+        assert_eq!(output, "20 text/gemini\r\n");
+        served.join().expect("upstream")?;
+        Ok(())
+    }
+
+    #[test]
+    fn urls_may_be_rewritten() -> Result<()> {
+        let (upstream, served) = upstream("30 /elsewhere\r\n")?;
+        let port = upstream.port().expect("port");
+        let handler = ProxyHandler::new(upstream.join("base/")?, "app")?.with_rewrite(true);
+
+        let output = get(&handler, "gemini://example.com/app/notes?q")?.expect("handled");
+
+        assert_eq!(output, "30 /elsewhere\r\n");
+        assert_eq!(
+            served.join().expect("upstream")?,
+            format!("gemini://localhost:{port}/base/notes?q\r\n")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn an_unreachable_upstream_is_a_proxy_error() -> Result<()> {
+        let port = TcpListener::bind("127.0.0.1:0")?.local_addr()?.port();
+        let handler = ProxyHandler::new(format!("gemini://127.0.0.1:{port}/").parse()?, "/")?;
+
+        let output = get(&handler, "gemini://example.com/")?.expect("handled");
+
+        assert_eq!(output, "43 TemporaryFailure(ProxyError)\r\n");
+        Ok(())
+    }
+
+    #[test]
+    fn a_silent_upstream_is_a_proxy_error() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let upstream = format!("gemini://127.0.0.1:{}/", listener.local_addr()?.port());
+        let handler =
+            ProxyHandler::new(upstream.parse()?, "/")?.with_timeout(Duration::from_millis(100));
+
+        let output = get(&handler, "gemini://example.com/")?.expect("handled");
+
+        assert_eq!(output, "43 TemporaryFailure(ProxyError)\r\n");
+        Ok(())
+    }
+
+    #[test]
+    fn only_matching_requests_are_proxied() -> Result<()> {
+        let handler =
+            ProxyHandler::new("gemini://localhost/".parse()?, "app")?.for_host("Example.com");
+
+        for url in [
+            "gemini://example.com/static/foo",
+            "gemini://elsewhere.com/app/foo",
+        ] {
+            let request: Request = format!("{url}\r\n").parse()?;
+            assert!(handler.handle_request(&request).is_none(), "{url}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn upstreams_must_be_gemini_servers() {
+        assert!(matches!(
+            ProxyHandler::new("https://localhost/".parse().unwrap(), "/"),
+            Err(ProxyHandlerError::WrongScheme(_))
+        ));
+    }
+}
@@ -0,0 +1,426 @@
+use std::collections::HashSet;
+
+use log::{debug, warn};
+use regex::Regex;
+use url::Url;
+
+use crate::{
+    handler::Handler,
+    request::Request,
+    response::{ErrResponse, Response},
+    status::{PermanentFailure, Redirect, Status},
+};
+
+/// How many redirects in a row a client may be asked to follow.
+pub const MAX_REDIRECTS: usize = 5;
+
+/// The most a response's meta may hold, in bytes.
+const MAX_META: usize = 1024;
+
+/// Where we pretend requests are for when following rules on load.
+const LOCAL: &str = "gemini://inimeg.invalid/";
+
+#[derive(Debug)]
+enum Source {
+    Exact(String),
+    Prefix(String),
+    Regex(Regex),
+}
+
+/// Sends requests whose paths match somewhere else.
+///
+/// Targets are sent as written, so they may be absolute urls, for another
+/// host, or paths relative to the request.
+#[derive(Debug)]
+pub struct RedirectRule {
+    source: Source,
+    to: String,
+    permanent: bool,
+    /// A path to check for loops from, besides an exact or prefix rule's
+    /// own.
+    example: Option<String>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum RedirectError {
+    #[error("Invalid pattern `{pattern}`: {source}")]
+    Pattern {
+        pattern: String,
+        source: regex::Error,
+    },
+    #[error("Redirect target `{0}` is longer than {MAX_META} bytes")]
+    TooLong(String),
+    #[error("Redirects from `{0}` lead back to where they started")]
+    Loop(String),
+    #[error("Redirects from `{0}` go on for more than {MAX_REDIRECTS} steps")]
+    TooMany(String),
+    #[error("`{0}` is given as an example but isn't redirected by its rule")]
+    Unmatched(String),
+}
+
+impl RedirectRule {
+    /// Redirect requests for exactly `path`.
+    pub fn exact(path: impl Into<String>, to: impl Into<String>) -> Self {
+        Self {
+            source: Source::Exact(path.into()),
+            to: to.into(),
+            permanent: false,
+            example: None,
+        }
+    }
+
+    /// Redirect everything under `prefix`, replacing it with `to`.
+    pub fn prefix(prefix: impl Into<String>, to: impl Into<String>) -> Self {
+        Self {
+            source: Source::Prefix(prefix.into()),
+            to: to.into(),
+            permanent: false,
+            example: None,
+        }
+    }
+
+    /// Redirect paths matching the whole of `pattern`. `$1` or `${name}` in
+    /// `to` stand for what its groups captured.
+    pub fn regex(pattern: &str, to: impl Into<String>) -> Result<Self, RedirectError> {
+        let regex =
+            Regex::new(&format!("^(?:{pattern})$")).map_err(|source| RedirectError::Pattern {
+                pattern: pattern.into(),
+                source,
+            })?;
+        Ok(Self {
+            source: Source::Regex(regex),
+            to: to.into(),
+            permanent: false,
+            example: None,
+        })
+    }
+
+    /// Answer with 31 rather than 30, so that clients update their links.
+    pub fn with_permanent(mut self, permanent: bool) -> Self {
+        self.permanent = permanent;
+        self
+    }
+
+    /// Also check for loops from `path`, which this rule must redirect.
+    ///
+    /// We can't tell which paths a regex will match, so regex rules are
+    /// only checked from their examples.
+    pub fn with_example(mut self, path: impl Into<String>) -> Self {
+        self.example = Some(path.into());
+        self
+    }
+
+    /// Where a request for `path` should go instead, if this rule covers it.
+    fn target(&self, path: &str) -> Option<String> {
+        match &self.source {
+            Source::Exact(exact) => (path == exact).then(|| self.to.clone()),
+            Source::Prefix(prefix) => path
+                .strip_prefix(prefix.as_str())
+                .map(|rest| format!("{}{rest}", self.to)),
+            Source::Regex(regex) => regex.captures(path).map(|captures| {
+                let mut target = String::new();
+                captures.expand(&self.to, &mut target);
+                target
+            }),
+        }
+    }
+
+    /// Paths this rule redirects, to follow the rules from on load.
+    fn probes(&self) -> impl Iterator<Item = &str> {
+        let own = match &self.source {
+            Source::Exact(path) | Source::Prefix(path) => Some(path.as_str()),
+            Source::Regex(_) => None,
+        };
+        own.into_iter().chain(self.example.as_deref())
+    }
+
+    fn status(&self) -> Status {
+        Status::Redirect(if self.permanent {
+            Redirect::Permanent
+        } else {
+            Redirect::Temporary
+        })
+    }
+}
+
+/// Answers requests for content which has moved, or gone for good.
+///
+/// Rules are tried in order and the first to match wins. A request's query
+/// is passed on, unless the target has its own.
+#[derive(Debug, Default)]
+pub struct RedirectHandler {
+    rules: Vec<RedirectRule>,
+    /// Paths answered with 52.
+    gone: HashSet<String>,
+    /// The names of the host we're serving, so that we can tell which
+    /// absolute targets lead back to us.
+    hosts: HashSet<String>,
+}
+
+impl RedirectHandler {
+    /// Fails if a rule's target can't fit in a response, or if following
+    /// the rules from a path they cover leads round in circles or on for
+    /// longer than [`MAX_REDIRECTS`]. This is best effort: regex rules are
+    /// only followed from their examples, see [`RedirectRule::with_example`].
+    pub fn new(
+        rules: Vec<RedirectRule>,
+        gone: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Result<Self, RedirectError> {
+        let handler = Self {
+            rules,
+            gone: gone.into_iter().map(Into::into).collect(),
+            hosts: HashSet::new(),
+        };
+        handler.check()?;
+        Ok(handler)
+    }
+
+    /// Count absolute targets on any of `hosts` as our own when checking
+    /// for loops, as they are if we serve those names.
+    pub fn with_hosts(
+        mut self,
+        hosts: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Result<Self, RedirectError> {
+        self.hosts = hosts
+            .into_iter()
+            .map(|host| host.into().to_lowercase())
+            .collect();
+        self.check()?;
+        Ok(self)
+    }
+
+    fn check(&self) -> Result<(), RedirectError> {
+        for rule in &self.rules {
+            if rule.to.len() > MAX_META {
+                return Err(RedirectError::TooLong(rule.to.clone()));
+            }
+            if let Some(example) = &rule.example
+                && rule.target(example).is_none()
+            {
+                return Err(RedirectError::Unmatched(example.clone()));
+            }
+            for path in rule.probes() {
+                self.follow(path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Follow the rules from `path` for as long as they stay on this host.
+    fn follow(&self, path: &str) -> Result<(), RedirectError> {
+        let local = Url::parse(LOCAL).expect("valid url");
+        let Ok(mut url) = local.join(path) else {
+            return Ok(());
+        };
+        let mut seen = HashSet::new();
+        for _ in 0..=MAX_REDIRECTS {
+            if !seen.insert(url.path().to_owned()) {
+                return Err(RedirectError::Loop(path.into()));
+            } else if self.gone.contains(url.path()) {
+                return Ok(());
+            }
+            let Some(target) = self.rules.iter().find_map(|rule| rule.target(url.path())) else {
+                return Ok(());
+            };
+            let next = match Url::parse(&target) {
+                Ok(absolute) if self.is_ours(&absolute) => local.join(absolute.path()),
+                Ok(_) => return Ok(()),
+                Err(_) => url.join(&target),
+            };
+            match next {
+                Ok(next) => url = next,
+                Err(_) => return Ok(()),
+            }
+        }
+        Err(RedirectError::TooMany(path.into()))
+    }
+
+    /// Whether `url` is for one of our hosts, on the default port.
+    fn is_ours(&self, url: &Url) -> bool {
+        url.scheme() == "gemini"
+            && url.port().is_none_or(|port| port == 1965)
+            && url
+                .host_str()
+                .is_some_and(|host| self.hosts.contains(&host.to_lowercase()))
+    }
+}
+
+impl Handler for RedirectHandler {
+    fn handle_request(&self, request: &Request) -> Option<Response> {
+        let url = request.url();
+        if self.gone.contains(url.path()) {
+            return Some(Response::Err(ErrResponse::from_status(
+                Status::PermanentFailure(PermanentFailure::Gone),
+            )));
+        }
+        let (rule, mut target) = self
+            .rules
+            .iter()
+            .find_map(|rule| Some((rule, rule.target(url.path())?)))?;
+        if let Some(query) = url.query()
+            && !target.contains('?')
+        {
+            target = format!("{target}?{query}");
+        }
+        if target.len() > MAX_META {
+            warn!("Redirect from '{}' is too long to send", url.path());
+            return Some(Response::Err(ErrResponse::from_status(
+                Status::PermanentFailure(PermanentFailure::BadRequest),
+            )));
+        }
+        debug!("Redirecting '{}' to '{target}'", url.path());
+        Some(Response::Err(ErrResponse {
+            status: rule.status(),
+            msg: Some(target.into()),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::get;
+    use anyhow::Result;
+    use rstest::rstest;
+
+    fn handler() -> Result<RedirectHandler> {
+        Ok(RedirectHandler::new(
+            vec![
+                RedirectRule::exact("/old.gmi", "/new.gmi").with_permanent(true),
+                RedirectRule::exact("/about", "about/"),
+                RedirectRule::prefix("/blog/", "gemini://blog.example.org/"),
+                RedirectRule::regex(r"/posts/(\d{4})/(?<slug>[^/]+)\.gmi", "/$1/${slug}/")?,
+            ],
+            ["/removed.gmi"],
+        )?)
+    }
+
+    #[rstest]
+    #[case::exact("/old.gmi", "31 /new.gmi\r\n")]
+    #[case::relative("/about", "30 about/\r\n")]
+    #[case::other_host("/blog/2024/hello", "30 gemini://blog.example.org/2024/hello\r\n")]
+    #[case::captures("/posts/2024/hello.gmi", "30 /2024/hello/\r\n")]
+    #[case::query(
+        "/blog/search?gemini",
+        "30 gemini://blog.example.org/search?gemini\r\n"
+    )]
+    #[case::gone("/removed.gmi", "52 PermanentFailure(Gone)\r\n")]
+    fn requests_are_redirected_by_the_first_matching_rule(
+        #[case] path: &str,
+        #[case] expected: &str,
+    ) -> Result<()> {
+        let output = get(&handler()?, &format!("gemini://example.com{path}"))?;
+
+        assert_eq!(output.as_deref(), Some(expected));
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::exact("/old.gmi/")]
+    #[case::partial_match("/posts/2024/hello.gmi/comments")]
+    #[case::elsewhere("/index.gmi")]
+    fn other_requests_are_left_alone(#[case] path: &str) -> Result<()> {
+        let output = get(&handler()?, &format!("gemini://example.com{path}"))?;
+
+        assert_eq!(output, None);
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::to_itself(vec![RedirectRule::exact("/a", "/a")])]
+    #[case::round_trip(vec![RedirectRule::exact("/a", "/b"), RedirectRule::exact("/b", "/a")])]
+    #[case::relative(vec![RedirectRule::exact("/dir/a", "b"), RedirectRule::exact("/dir/b", "a")])]
+    #[case::absolute(vec![
+        RedirectRule::exact("/a", "/b"),
+        RedirectRule::prefix("/b", "gemini://Example.org/a"),
+    ])]
+    #[case::regex(vec![RedirectRule::regex("^/(.*)$", "/$1").expect("regex").with_example("/a")])]
+    #[case::regex_and_exact(vec![
+        RedirectRule::exact("/a", "/b"),
+        RedirectRule::regex("^/b$", "/a").expect("regex"),
+    ])]
+    #[case::regexes(vec![
+        RedirectRule::regex("/x/(?<rest>.+)", "/y/${rest}").expect("regex").with_example("/x/a"),
+        RedirectRule::regex("/y/([a-z]+)", "/x/$1").expect("regex"),
+    ])]
+    fn loops_are_refused(#[case] rules: Vec<RedirectRule>) {
+        let handler = RedirectHandler::new(rules, Vec::<String>::new())
+            .and_then(|handler| handler.with_hosts(["example.org"]));
+
+        assert!(
+            matches!(handler, Err(RedirectError::Loop(_))),
+            "{handler:?}"
+        );
+    }
+
+    #[test]
+    fn regex_rules_are_only_checked_from_examples() -> Result<()> {
+        let looping = || RedirectRule::regex("/(.*)", "/$1");
+
+        RedirectHandler::new(vec![looping()?], Vec::<String>::new())?;
+        let handler =
+            RedirectHandler::new(vec![looping()?.with_example("/a")], Vec::<String>::new());
+        assert!(matches!(handler, Err(RedirectError::Loop(_))));
+        let handler = RedirectHandler::new(
+            vec![RedirectRule::regex(r"/(\d+)", "/posts/$1")?.with_example("/a")],
+            Vec::<String>::new(),
+        );
+        assert!(matches!(handler, Err(RedirectError::Unmatched(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn long_chains_are_refused() {
+        let growing = vec![RedirectRule::prefix("/a/", "/a/a/")];
+
+        let handler = RedirectHandler::new(growing, Vec::<String>::new());
+
+        assert!(
+            matches!(handler, Err(RedirectError::TooMany(_))),
+            "{handler:?}"
+        );
+    }
+
+    #[test]
+    fn chains_ending_elsewhere_are_fine() -> Result<()> {
+        let rules = vec![
+            RedirectRule::exact("/a", "/b"),
+            RedirectRule::exact("/b", "gemini://example.org/a"),
+            RedirectRule::exact("/c", "/gone"),
+            RedirectRule::regex(r"/posts/(\d{4})/(.+)\.gmi", "/$1/$2/")?,
+        ];
+
+        RedirectHandler::new(rules, ["/gone"])?.with_hosts(["example.com"])?;
+        Ok(())
+    }
+
+    #[test]
+    fn targets_must_fit_in_a_response() -> Result<()> {
+        let long = format!("/{}", "a".repeat(MAX_META));
+
+        let handler = RedirectHandler::new(vec![RedirectRule::exact("/a", long)], ["/gone"]);
+        assert!(matches!(handler, Err(RedirectError::TooLong(_))));
+
+        let handler = RedirectHandler::new(
+            vec![RedirectRule::regex("/long/(.*)", "/$1$1")?],
+            Vec::<String>::new(),
+        )?;
+        let output = get(
+            &handler,
+            &format!("gemini://e.com/long/{}", "a".repeat(600)),
+        )?;
+        assert_eq!(
+            output.as_deref(),
+            Some("59 PermanentFailure(BadRequest)\r\n")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_patterns_are_refused() {
+        assert!(matches!(
+            RedirectRule::regex("/(unclosed", "/"),
+            Err(RedirectError::Pattern { .. })
+        ));
+    }
+}
@@ -1,15 +1,62 @@
-use std::str::FromStr;
+use std::{collections::HashMap, net::SocketAddr, str::FromStr};
 
 use url::Url;
 
-use crate::status::{PermanentFailure, Status};
-
-#[derive(Debug, PartialEq)]
-pub struct Request(Url);
+use crate::{
+    client_cert::ClientCertificate,
+    status::{PermanentFailure, Status},
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Request {
+    url: Url,
+    client_certificate: Option<ClientCertificate>,
+    peer_addr: Option<SocketAddr>,
+    /// What a [`Router`](crate::Router) captured from the path.
+    params: HashMap<String, String>,
+}
 
 impl Request {
     pub fn url(&self) -> &Url {
-        &self.0
+        &self.url
+    }
+
+    /// The same request from the same client, but for `url`.
+    pub fn with_url(mut self, url: Url) -> Self {
+        self.url = url;
+        self
+    }
+
+    /// The certificate the client identified itself with, if any.
+    pub fn client_certificate(&self) -> Option<&ClientCertificate> {
+        self.client_certificate.as_ref()
+    }
+
+    pub fn with_client_certificate(mut self, certificate: Option<ClientCertificate>) -> Self {
+        self.client_certificate = certificate;
+        self
+    }
+
+    /// The address the request came from, if known.
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr
+    }
+
+    pub fn with_peer_addr(mut self, addr: Option<SocketAddr>) -> Self {
+        self.peer_addr = addr;
+        self
+    }
+
+    /// The part of the path captured as `name` by the route this request
+    /// was dispatched through, percent-decoded.
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params.get(name).map(String::as_str)
+    }
+
+    /// Add to the params captured so far, replacing any of the same name.
+    pub fn with_params(mut self, params: HashMap<String, String>) -> Self {
+        self.params.extend(params);
+        self
     }
 }
 
@@ -63,7 +110,12 @@ impl FromStr for Request {
         if url.path().is_empty() {
             url.set_path("/");
         }
-        Ok(Request(url))
+        Ok(Request {
+            url,
+            client_certificate: None,
+            peer_addr: None,
+            params: HashMap::new(),
+        })
     }
 }
 
@@ -134,7 +186,7 @@ mod a_valid_request {
     fn is_parsed() {
         let url = Request::from_str("gemini://example.com/foo/bar/baz\r\n")
             .unwrap()
-            .0;
+            .url;
         assert_eq!(
             url.to_string(),
             String::from("gemini://example.com/foo/bar/baz")
@@ -143,7 +195,7 @@ mod a_valid_request {
 
     #[test]
     fn has_a_missing_path_added() {
-        let url = Request::from_str("gemini://example.com\r\n").unwrap().0;
+        let url = Request::from_str("gemini://example.com\r\n").unwrap().url;
         assert_eq!(url.to_string(), String::from("gemini://example.com/"));
     }
 }
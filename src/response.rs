@@ -1,8 +1,15 @@
-use std::io::Write;
+use std::io::{ErrorKind, Read, Write};
 
 use bytes::Bytes;
 
-use crate::status::{Status, Success};
+use crate::{
+    mime::Mime,
+    status::{Status, Success},
+};
+
+/// The longest a response header may be: two digits, a space, 1024 bytes
+/// of meta and CRLF.
+pub const MAX_HEADER: u64 = 1029;
 
 pub struct ErrResponse {
     pub status: Status,
@@ -20,28 +27,52 @@ impl ErrResponse {
 
 pub struct SuccessResponse {
     pub status: Success,
-    pub mime: String,
+    pub mime: Mime,
     pub body: Bytes,
 }
 
 pub struct FileResponse {
     pub status: Success,
-    pub mime: &'static str,
+    pub mime: Mime,
     pub file: std::fs::File,
 }
 
-// impl From<FileResponse>
+/// A body sent as it is read, for documents too large to buffer or which
+/// are still being generated.
+pub struct StreamingResponse {
+    pub status: Success,
+    pub mime: Mime,
+    pub body: Box<dyn Read + Send>,
+}
 
-// TODO: do we really want a trait?
-// pub struct StreamingResponse<T: Read> {
-//     body: T
-// }
+/// A response sent verbatim, status line and all, as written by a CGI script
+/// or another server.
+pub struct RawResponse {
+    pub body: Box<dyn Read + Send>,
+}
 
 pub enum Response {
     Err(ErrResponse),
     Fixed(SuccessResponse),
     Disk(FileResponse),
-    // Streaming(Box<dyn StreamingResponse>)
+    Streaming(StreamingResponse),
+    Raw(RawResponse),
+}
+
+/// Copy `body` to `writer`, flushing each chunk so that the client sees it
+/// as soon as we have it.
+fn stream<R: Read, W: Write>(mut body: R, mut writer: W) -> std::io::Result<()> {
+    let mut buf = [0; 8192];
+    loop {
+        let read = match body.read(&mut buf) {
+            Ok(0) => return Ok(()),
+            Ok(read) => read,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        writer.write_all(&buf[..read])?;
+        writer.flush()?;
+    }
 }
 
 impl Response {
@@ -68,6 +99,14 @@ impl Response {
                 write!(writer, "{} {}\r\n", Status::Success(status), mime)?;
                 std::io::copy(&mut file, &mut writer)?;
             }
+            Self::Streaming(StreamingResponse { status, mime, body }) => {
+                write!(writer, "{} {}\r\n", Status::Success(status), mime)?;
+                // The body may be slow in coming, and the client needn't
+                // wait for it to hear that it's on its way.
+                writer.flush()?;
+                stream(body, writer)?;
+            }
+            Self::Raw(RawResponse { body }) => stream(body, writer)?,
         }
         Ok(())
     }
@@ -120,4 +159,75 @@ line4
         );
         Ok(())
     }
+
+    /// Hands out its chunks one read at a time, then fails if asked to.
+    struct Chunks(Vec<&'static str>, bool);
+
+    impl Read for Chunks {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            match self.0.pop() {
+                Some(chunk) => {
+                    buf[..chunk.len()].copy_from_slice(chunk.as_bytes());
+                    Ok(chunk.len())
+                }
+                None if self.1 => Err(std::io::Error::other("backend died")),
+                None => Ok(0),
+            }
+        }
+    }
+
+    /// Records what had been written by each flush.
+    #[derive(Default)]
+    struct Flushes {
+        written: Vec<u8>,
+        flushed: Vec<String>,
+    }
+
+    impl Write for Flushes {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.written.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            self.flushed
+                .push(String::from_utf8_lossy(&self.written).into_owned());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn a_streaming_response_is_flushed_as_it_is_read() -> Result<()> {
+        let mut writer = Flushes::default();
+
+        let resp = StreamingResponse {
+            status: Success::Generic,
+            mime: "text/gemini".into(),
+            body: Box::new(Chunks(vec!["world\n", "hello "], false)),
+        };
+        Response::Streaming(resp).send(&mut writer)?;
+
+        assert_eq!(
+            writer.flushed,
+            [
+                "20 text/gemini\r\n",
+                "20 text/gemini\r\nhello ",
+                "20 text/gemini\r\nhello world\n"
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn a_failing_stream_is_an_error() {
+        let resp = StreamingResponse {
+            status: Success::Generic,
+            mime: "text/gemini".into(),
+            body: Box::new(Chunks(vec!["partial"], true)),
+        };
+
+        let sent = Response::Streaming(resp).send(Vec::new());
+
+        assert_eq!(sent.unwrap_err().to_string(), "backend died");
+    }
 }
@@ -0,0 +1,351 @@
+use std::{collections::HashMap, fmt};
+
+use log::debug;
+use percent_encoding::percent_decode_str;
+use url::Url;
+
+use crate::{handler::Handler, request::Request, response::Response};
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Literal(String),
+    /// `:name`, capturing one segment.
+    Param(String),
+    /// `*name`, capturing whatever is left.
+    Rest(String),
+}
+
+impl Segment {
+    /// Literals are more specific than parameters, which are more specific
+    /// than the rest of the path.
+    fn rank(&self) -> u8 {
+        match self {
+            Self::Literal(_) => 0,
+            Self::Param(_) => 1,
+            Self::Rest(_) => 2,
+        }
+    }
+}
+
+/// Which requests a [`Route`] takes, by whether they have a query.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum QueryRule {
+    #[default]
+    Any,
+    Present,
+    Absent,
+}
+
+/// A path pattern, such as `/users/:name/posts/*rest`, and optionally a host
+/// and whether there must be a query.
+///
+/// `:name` matches one segment and `*name` the rest of the path, perhaps
+/// nothing. What they match is given to the handler as a
+/// [`Request::param`].
+#[derive(Debug, Clone)]
+pub struct Route {
+    pattern: String,
+    segments: Vec<Segment>,
+    /// The only host this route matches, if any.
+    host: Option<String>,
+    query: QueryRule,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum RouteError {
+    #[error("Route `{0}` doesn't start with a slash")]
+    Relative(String),
+    #[error("Route `{0}` has a parameter without a name")]
+    Unnamed(String),
+    #[error("Route `{0}` has more after `*`")]
+    RestNotLast(String),
+    #[error("Route `{pattern}` captures `{name}` twice")]
+    Duplicate { pattern: String, name: String },
+}
+
+impl Route {
+    pub fn new(pattern: &str) -> Result<Self, RouteError> {
+        let Some(path) = pattern.strip_prefix('/') else {
+            return Err(RouteError::Relative(pattern.into()));
+        };
+        let mut segments = Vec::new();
+        for segment in path.split('/') {
+            if matches!(segments.last(), Some(Segment::Rest(_))) {
+                return Err(RouteError::RestNotLast(pattern.into()));
+            }
+            let segment = match segment.split_at_checked(1) {
+                Some((":", name)) => Segment::Param(name.into()),
+                Some(("*", name)) => Segment::Rest(name.into()),
+                _ => Segment::Literal(segment.into()),
+            };
+            if let Segment::Param(name) | Segment::Rest(name) = &segment {
+                if name.is_empty() {
+                    return Err(RouteError::Unnamed(pattern.into()));
+                }
+                let duplicate = segments.iter().any(|other| {
+                    matches!(other, Segment::Param(other) | Segment::Rest(other) if other == name)
+                });
+                if duplicate {
+                    return Err(RouteError::Duplicate {
+                        pattern: pattern.into(),
+                        name: name.clone(),
+                    });
+                }
+            }
+            segments.push(segment);
+        }
+        Ok(Self {
+            pattern: pattern.into(),
+            segments,
+            host: None,
+            query: QueryRule::Any,
+        })
+    }
+
+    /// Only match requests for `host`.
+    pub fn for_host(mut self, host: impl Into<String>) -> Self {
+        self.host = Some(host.into().to_lowercase());
+        self
+    }
+
+    pub fn with_query(mut self, query: QueryRule) -> Self {
+        self.query = query;
+        self
+    }
+
+    /// What the pattern captured from `url`, if this route matches it.
+    fn matches(&self, url: &Url) -> Option<HashMap<String, String>> {
+        if self
+            .host
+            .as_ref()
+            .is_some_and(|host| url.host_str() != Some(host))
+        {
+            return None;
+        }
+        match (self.query, url.query()) {
+            (QueryRule::Present, None) | (QueryRule::Absent, Some(_)) => return None,
+            _ => {}
+        }
+        let path = url.path().strip_prefix('/')?;
+        let mut parts = path.split('/');
+        let mut params = HashMap::new();
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(literal) => {
+                    if parts.next()? != literal {
+                        return None;
+                    }
+                }
+                Segment::Param(name) => {
+                    let part = parts.next().filter(|part| !part.is_empty())?;
+                    params.insert(name.clone(), decode(part));
+                }
+                Segment::Rest(name) => {
+                    let rest = parts.by_ref().collect::<Vec<_>>().join("/");
+                    params.insert(name.clone(), decode(&rest));
+                }
+            }
+        }
+        parts.next().is_none().then_some(params)
+    }
+
+    /// Routes which sort first are tried first: those for a particular host,
+    /// then those whose segments are more specific, then those particular
+    /// about queries.
+    fn specificity(&self) -> (bool, Vec<u8>, bool) {
+        (
+            self.host.is_none(),
+            self.segments.iter().map(Segment::rank).collect(),
+            self.query == QueryRule::Any,
+        )
+    }
+}
+
+fn decode(part: &str) -> String {
+    percent_decode_str(part).decode_utf8_lossy().into_owned()
+}
+
+impl fmt::Display for Route {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", self.host.as_deref().unwrap_or("*"), self.pattern)?;
+        match self.query {
+            QueryRule::Any => Ok(()),
+            QueryRule::Present => write!(f, " (with query)"),
+            QueryRule::Absent => write!(f, " (without query)"),
+        }
+    }
+}
+
+/// Dispatches requests to handlers by [`Route`].
+///
+/// Routes are tried from the most specific to the least, whatever order
+/// they were added in; equally specific ones in the order they were added.
+/// If a route's handler declines a request the next matching route gets a
+/// chance.
+#[derive(Default)]
+pub struct Router {
+    routes: Vec<(Route, Box<dyn Handler>)>,
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn route(mut self, route: Route, handler: Box<dyn Handler>) -> Self {
+        self.routes.push((route, handler));
+        self.routes
+            .sort_by_cached_key(|(route, _)| route.specificity());
+        self
+    }
+
+    /// Our routes, in the order they're tried.
+    pub fn routes(&self) -> impl Iterator<Item = &Route> {
+        self.routes.iter().map(|(route, _)| route)
+    }
+}
+
+impl fmt::Debug for Router {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(self.routes().map(ToString::to_string))
+            .finish()
+    }
+}
+
+impl fmt::Display for Router {
+    /// One route per line, in the order they're tried.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for route in self.routes() {
+            writeln!(f, "{route}")?;
+        }
+        Ok(())
+    }
+}
+
+impl Handler for Router {
+    fn handle_request(&self, request: &Request) -> Option<Response> {
+        self.routes.iter().find_map(|(route, handler)| {
+            let params = route.matches(request.url())?;
+            debug!("Request for '{}' matches route {route}", request.url());
+            handler.handle_request(&request.clone().with_params(params))
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::{Named, get};
+    use anyhow::Result;
+    use rstest::rstest;
+
+    fn named(name: &'static str) -> Box<dyn Handler> {
+        Box::new(Named(name))
+    }
+
+    struct Declines;
+
+    impl Handler for Declines {
+        fn handle_request(&self, _request: &Request) -> Option<Response> {
+            None
+        }
+    }
+
+    fn router() -> Result<Router> {
+        Ok(Router::new()
+            .route(Route::new("/*rest")?, named("fallback"))
+            .route(Route::new("/users/:name/posts/*rest")?, named("posts"))
+            .route(Route::new("/users/:name")?, named("user"))
+            .route(Route::new("/users/new")?, named("new user"))
+            .route(
+                Route::new("/search")?.with_query(QueryRule::Present),
+                named("results"),
+            )
+            .route(
+                Route::new("/search")?.with_query(QueryRule::Absent),
+                named("search form"),
+            )
+            .route(
+                Route::new("/users/:name")?.for_host("Admin.example.com"),
+                named("admin"),
+            ))
+    }
+
+    /// The body `handler` answers `url` with.
+    fn body(handler: &dyn Handler, url: &str) -> Result<Option<String>> {
+        Ok(get(handler, url)?
+            .and_then(|output| output.strip_prefix("20 text/plain\r\n").map(str::to_owned)))
+    }
+
+    #[rstest]
+    #[case::literal("gemini://example.com/users/new", "new user")]
+    #[case::param("gemini://example.com/users/ada", "user name=ada")]
+    #[case::decoded("gemini://example.com/users/J%C3%BCrgen", "user name=Jürgen")]
+    #[case::rest(
+        "gemini://example.com/users/ada/posts/2024/hello.gmi",
+        "posts name=ada rest=2024/hello.gmi"
+    )]
+    #[case::empty_rest("gemini://example.com/users/ada/posts", "posts name=ada rest=")]
+    #[case::host("gemini://admin.example.com/users/ada", "admin name=ada")]
+    #[case::query("gemini://example.com/search?gemini", "results")]
+    #[case::no_query("gemini://example.com/search", "search form")]
+    #[case::fallback("gemini://example.com/users/", "fallback rest=users/")]
+    fn requests_go_to_the_most_specific_route(
+        #[case] url: &str,
+        #[case] expected: &str,
+    ) -> Result<()> {
+        assert_eq!(body(&router()?, url)?.as_deref(), Some(expected));
+        Ok(())
+    }
+
+    #[test]
+    fn declined_requests_fall_through() -> Result<()> {
+        let router = Router::new()
+            .route(Route::new("/*rest")?, named("next"))
+            .route(Route::new("/:name")?, Box::new(Declines));
+
+        assert_eq!(
+            body(&router, "gemini://example.com/hello")?.as_deref(),
+            Some("next rest=hello")
+        );
+        assert_eq!(body(&Router::new(), "gemini://example.com/hello")?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn nested_routers_keep_outer_params() -> Result<()> {
+        let posts = Router::new().route(Route::new("/users/:who/posts/:id")?, named("post"));
+        let router = Router::new().route(Route::new("/users/:name/*rest")?, Box::new(posts));
+
+        assert_eq!(
+            body(&router, "gemini://example.com/users/ada/posts/7")?.as_deref(),
+            Some("post name=ada id=7 rest=posts/7")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn routes_are_listed_in_the_order_they_are_tried() -> Result<()> {
+        assert_eq!(
+            router()?.to_string(),
+            "admin.example.com/users/:name\n\
+             */search (with query)\n\
+             */search (without query)\n\
+             */users/new\n\
+             */users/:name\n\
+             */users/:name/posts/*rest\n\
+             */*rest\n"
+        );
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::relative("users/:name")]
+    #[case::unnamed("/users/:")]
+    #[case::rest_not_last("/files/*rest/edit")]
+    #[case::duplicate("/:name/:name")]
+    fn invalid_patterns_are_refused(#[case] pattern: &str) {
+        assert!(Route::new(pattern).is_err());
+    }
+}
@@ -0,0 +1,267 @@
+use std::{
+    io::{BufReader, Cursor, Read, Write},
+    path::PathBuf,
+    time::Duration,
+};
+
+use log::{debug, warn};
+
+use crate::{
+    cgi::{self, GatewayError},
+    handler::{Handler, Prefix},
+    net::connect_tcp,
+    request::Request,
+    response::{ErrResponse, RawResponse, Response},
+    status::{Status, TemporaryFailure},
+};
+
+/// How long the backend may take to accept a connection, and to answer.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where an SCGI application listens.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ScgiBackend {
+    /// A `host:port`.
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+impl From<&str> for ScgiBackend {
+    /// `unix:/some/path` for a Unix socket, otherwise a TCP `host:port`.
+    fn from(value: &str) -> Self {
+        match value.strip_prefix("unix:") {
+            Some(path) => Self::Unix(path.into()),
+            None => Self::Tcp(value.into()),
+        }
+    }
+}
+
+/// Forwards requests under a prefix to a long-running SCGI application.
+///
+/// The application gets the same variables a CGI script would, with the
+/// prefix as `SCRIPT_NAME`, and its reply is sent to the client as is: it
+/// must begin with a Gemini status line.
+#[derive(Debug)]
+pub struct ScgiHandler {
+    backend: ScgiBackend,
+    /// The prefix required in the url for this handler to match.
+    prefix: Prefix,
+    /// How long to wait to connect, and between reads or writes.
+    timeout: Duration,
+}
+
+impl ScgiHandler {
+    pub fn new(backend: ScgiBackend, prefix: impl Into<String>) -> Self {
+        Self {
+            backend,
+            prefix: Prefix::from(prefix.into()),
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Send `headers` to the backend, returning the connection to read its
+    /// reply from.
+    fn connect(&self, headers: &[u8]) -> std::io::Result<Box<dyn Read + Send>> {
+        match &self.backend {
+            ScgiBackend::Tcp(addr) => {
+                let mut stream = connect_tcp(addr, self.timeout)?;
+                stream.set_read_timeout(Some(self.timeout))?;
+                stream.set_write_timeout(Some(self.timeout))?;
+                stream.write_all(headers)?;
+                Ok(Box::new(stream))
+            }
+            #[cfg(unix)]
+            ScgiBackend::Unix(path) => {
+                let mut stream = std::os::unix::net::UnixStream::connect(path)?;
+                stream.set_read_timeout(Some(self.timeout))?;
+                stream.set_write_timeout(Some(self.timeout))?;
+                stream.write_all(headers)?;
+                Ok(Box::new(stream))
+            }
+            #[cfg(not(unix))]
+            ScgiBackend::Unix(_) => Err(std::io::ErrorKind::Unsupported.into()),
+        }
+    }
+
+    fn forward(&self, request: &Request) -> Result<Response, GatewayError> {
+        let path = request.url().path();
+        // The prefix always ends in a slash, which belongs to PATH_INFO.
+        let split = self.prefix.len() - 1;
+        let headers = netstring(&cgi::variables(request, &path[..split], &path[split..]));
+        let stream = self.connect(&headers).map_err(GatewayError::Connect)?;
+        let mut reply = BufReader::new(stream);
+        let header = cgi::read_header(&mut reply)?;
+        Ok(Response::Raw(RawResponse {
+            body: Box::new(Cursor::new(header).chain(reply)),
+        }))
+    }
+}
+
+/// Encode `variables` as SCGI headers: a netstring of NUL-terminated names
+/// and values, `CONTENT_LENGTH` first.
+fn netstring(variables: &[(&str, String)]) -> Vec<u8> {
+    let mut headers = b"CONTENT_LENGTH\x000\x00SCGI\x001\x00".to_vec();
+    for (name, value) in variables {
+        headers.extend_from_slice(name.as_bytes());
+        headers.push(0);
+        headers.extend_from_slice(value.as_bytes());
+        headers.push(0);
+    }
+    let mut netstring = format!("{}:", headers.len()).into_bytes();
+    netstring.append(&mut headers);
+    netstring.push(b',');
+    netstring
+}
+
+impl Handler for ScgiHandler {
+    fn handle_request(&self, request: &Request) -> Option<Response> {
+        if !request.url().path().starts_with(&*self.prefix) {
+            return None;
+        }
+        debug!("Forwarding request to SCGI backend {:?}", self.backend);
+        Some(self.forward(request).unwrap_or_else(|e| {
+            warn!("SCGI backend {:?} failed: {e}", self.backend);
+            Response::Err(ErrResponse::from_status(Status::TemporaryFailure(
+                TemporaryFailure::ProxyError,
+            )))
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::{get, sent};
+    use anyhow::Result;
+    use std::{
+        collections::HashMap,
+        io::{BufRead, Write},
+        net::TcpListener,
+        thread::JoinHandle,
+    };
+
+    /// Read one SCGI request's headers.
+    fn read_headers(reader: &mut impl BufRead) -> Result<HashMap<String, String>> {
+        let mut length = Vec::new();
+        reader.read_until(b':', &mut length)?;
+        let length: usize = std::str::from_utf8(&length[..length.len() - 1])?.parse()?;
+        let mut headers = vec![0; length + 1];
+        reader.read_exact(&mut headers)?;
+        assert_eq!(headers.pop(), Some(b','));
+        let fields = String::from_utf8(headers)?;
+        assert!(fields.starts_with("CONTENT_LENGTH\x000\x00"), "{fields:?}");
+        let mut fields = fields.split_terminator('\0');
+        let mut headers = HashMap::new();
+        while let (Some(name), Some(value)) = (fields.next(), fields.next()) {
+            headers.insert(name.into(), value.into());
+        }
+        Ok(headers)
+    }
+
+    /// A backend answering one request with the headers it was sent.
+    fn echo_backend(listener: TcpListener) -> JoinHandle<Result<()>> {
+        std::thread::spawn(move || {
+            let (stream, _) = listener.accept()?;
+            let headers = read_headers(&mut BufReader::new(&stream))?;
+            let mut reply = String::from("20 text/plain\r\n");
+            for name in [
+                "SCGI",
+                "SCRIPT_NAME",
+                "PATH_INFO",
+                "QUERY_STRING",
+                "REMOTE_ADDR",
+            ] {
+                reply.push_str(&format!("{name}={}\n", headers[name]));
+            }
+            (&stream).write_all(reply.as_bytes())?;
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn requests_are_forwarded_over_tcp() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let backend = ScgiBackend::Tcp(listener.local_addr()?.to_string());
+        let served = echo_backend(listener);
+        let handler = ScgiHandler::new(backend, "app");
+        let request = "gemini://example.com/app/notes/1?edit\r\n"
+            .parse::<Request>()?
+            .with_peer_addr(Some("192.0.2.1:4321".parse()?));
+
+        let output = sent(handler.handle_request(&request).expect("handled"))?;
+
+        served.join().expect("backend thread")?;
+        assert_eq!(
+            output,
+            "20 text/plain\r\n\
+             SCGI=1\n\
+             SCRIPT_NAME=/app\n\
+             PATH_INFO=/notes/1\n\
+             QUERY_STRING=edit\n\
+             REMOTE_ADDR=192.0.2.1\n"
+        );
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn requests_are_forwarded_over_unix_sockets() -> Result<()> {
+        use std::os::unix::net::UnixListener;
+
+        let dir = tempfile::TempDir::new()?;
+        let path = dir.path().join("app.sock");
+        let listener = UnixListener::bind(&path)?;
+        let served = std::thread::spawn(move || -> Result<()> {
+            let (stream, _) = listener.accept()?;
+            let headers = read_headers(&mut BufReader::new(&stream))?;
+            write!(&stream, "20 text/plain\r\n{}", headers["GEMINI_URL"])?;
+            Ok(())
+        });
+        let handler =
+            ScgiHandler::new(ScgiBackend::from(&*format!("unix:{}", path.display())), "/");
+
+        let output = get(&handler, "gemini://example.com/")?.expect("handled");
+
+        served.join().expect("backend thread")?;
+        assert_eq!(output, "20 text/plain\r\ngemini://example.com/");
+        Ok(())
+    }
+
+    #[test]
+    fn an_unreachable_backend_is_a_proxy_error() -> Result<()> {
+        let addr = TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+        let handler = ScgiHandler::new(ScgiBackend::Tcp(addr.to_string()), "/");
+
+        let output = get(&handler, "gemini://example.com/")?.expect("handled");
+
+        assert_eq!(output, "43 TemporaryFailure(ProxyError)\r\n");
+        Ok(())
+    }
+
+    #[test]
+    fn a_silent_backend_is_a_proxy_error() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let handler = ScgiHandler::new(ScgiBackend::Tcp(listener.local_addr()?.to_string()), "/")
+            .with_timeout(Duration::from_millis(100));
+
+        let output = get(&handler, "gemini://example.com/")?.expect("handled");
+
+        assert_eq!(output, "43 TemporaryFailure(ProxyError)\r\n");
+        Ok(())
+    }
+
+    #[test]
+    fn ignores_requests_not_starting_with_its_prefix() -> Result<()> {
+        let handler = ScgiHandler::new(ScgiBackend::from("127.0.0.1:1"), "app");
+
+        let request: Request = "gemini://example.com/static/foo\r\n".parse()?;
+
+        assert!(handler.handle_request(&request).is_none());
+        Ok(())
+    }
+}
@@ -1,24 +1,30 @@
 use crate::{
-    cli,
-    handler::Handler,
+    access_log::{AccessLog, Counting, Entry, rfc3339},
+    capture::{Exchange, Recorder, to_hex},
+    client_cert::ClientCertificate,
+    net::AcceptAnyCert,
+    pool::{PoolConfig, ThreadPool},
     request::{Request, RequestError},
     response::{ErrResponse, Response},
     status::*,
+    timeout::{TimeoutStream, Timeouts},
+    vhost::{SniResolver, VirtualHost, VirtualHostError, VirtualHosts},
 };
-use rustls::{
-    ServerConfig, ServerConnection, Stream,
-    pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject},
-};
+use rustls::{ServerConfig, ServerConnection, Stream};
 use std::{
-    io::{BufRead, Read},
-    net::{TcpListener, TcpStream},
+    io::{BufRead, ErrorKind, Read},
+    net::{SocketAddr, TcpListener, TcpStream},
     str::FromStr,
-    sync::Arc,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant},
 };
 
-use log::warn;
+use log::{debug, error, info, warn};
 
-type TlsStream<'a> = Stream<'a, ServerConnection, TcpStream>;
+type TlsStream<'a> = Stream<'a, ServerConnection, TimeoutStream>;
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
@@ -32,46 +38,391 @@ pub enum Error {
 
 type Result<T> = std::result::Result<T, Error>;
 
+/// The port Gemini is served on unless we're told otherwise.
+pub const DEFAULT_PORT: usize = 1965;
+
+/// A Gemini server: a listening socket and the capsules served on it.
+///
+/// Build one with [`Server::builder`].
 pub struct Server {
-    config: Arc<ServerConfig>,
     listener: TcpListener,
-    handlers: Vec<Box<dyn Handler>>,
+    hosts: VirtualHosts,
+    pool: PoolConfig,
+    timeouts: Timeouts,
+    /// Set to stop accepting connections and drain the ones in flight.
+    shutdown: Arc<AtomicBool>,
+    /// Set to reload every host's certificate from disk.
+    reload: Arc<AtomicBool>,
+    access_log: Option<AccessLog>,
+    capture: Option<Recorder>,
+}
+
+/// How long the accept loop sleeps between checks for signals.
+const ACCEPT_POLL: Duration = Duration::from_millis(100);
+
+/// Everything a worker needs to serve a connection.
+///
+/// Shared between workers once the server is running.
+pub(crate) struct Service {
+    hosts: VirtualHosts,
+    timeouts: Timeouts,
+    access_log: Option<AccessLog>,
+    capture: Option<Recorder>,
 }
 
-fn parse_raw_request<'a>(stream: &mut TlsStream<'a>) -> Result<String> {
+/// What became of a connection, as far as it got.
+#[derive(Debug, Default)]
+struct Served {
+    /// The host the client asked for in the handshake.
+    sni: Option<String>,
+    /// The client certificate's fingerprint, in hex.
+    fingerprint: Option<String>,
+    /// The client certificate, DER encoded as hex, if we're capturing.
+    certificate: Option<String>,
+    /// What the client sent, whether or not it made sense.
+    request: Option<Vec<u8>>,
+    bytes: u64,
+    status: Option<u8>,
+    header: Option<(u8, String)>,
+}
+
+/// Read the request line, or as much of it as a request may be.
+fn read_raw_request<'a>(stream: &mut TlsStream<'a>) -> std::io::Result<Vec<u8>> {
     let mut buf = Vec::with_capacity(1026);
     stream.take(1026).read_until(b'\n', &mut buf)?;
-    Ok(String::try_from(buf)?)
+    Ok(buf)
+}
+
+/// Configures a [`Server`] before it starts listening.
+#[derive(Debug)]
+pub struct ServerBuilder {
+    port: usize,
+    pool: PoolConfig,
+    timeouts: Timeouts,
+    hosts: Vec<VirtualHost>,
+    access_log: Option<AccessLog>,
+    capture: Option<Recorder>,
+}
+
+impl Default for ServerBuilder {
+    fn default() -> Self {
+        Self {
+            port: DEFAULT_PORT,
+            pool: PoolConfig::default(),
+            timeouts: Timeouts::default(),
+            hosts: Vec::new(),
+            access_log: None,
+            capture: None,
+        }
+    }
+}
+
+impl ServerBuilder {
+    /// Listen on `port`, on every interface. Zero picks a free port.
+    pub fn port(mut self, port: usize) -> Self {
+        self.port = port;
+        self
+    }
+
+    pub fn pool(mut self, pool: PoolConfig) -> Self {
+        self.pool = pool;
+        self
+    }
+
+    pub fn timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Serve a capsule, see [`Server::add_host`].
+    pub fn host(mut self, host: VirtualHost) -> Self {
+        self.hosts.push(host);
+        self
+    }
+
+    /// Log every response sent.
+    pub fn access_log(mut self, access_log: AccessLog) -> Self {
+        self.access_log = Some(access_log);
+        self
+    }
+
+    /// Record every request served, see [`Recorder`].
+    pub fn capture(mut self, capture: Recorder) -> Self {
+        self.capture = Some(capture);
+        self
+    }
+
+    /// Bind the listening socket.
+    pub fn build(self) -> anyhow::Result<Server> {
+        let mut server = Server::new(self.port, self.pool, self.timeouts)?;
+        server.access_log = self.access_log;
+        server.capture = self.capture;
+        for host in self.hosts {
+            server.add_host(host)?;
+        }
+        Ok(server)
+    }
 }
 
 impl Server {
-    pub fn new(
-        cert: CertificateDer<'static>,
-        key: PrivateKeyDer<'static>,
-        port: usize,
-    ) -> anyhow::Result<Self> {
-        let config = ServerConfig::builder()
-            .with_no_client_auth() // TODO
-            .with_single_cert(vec![cert], key)?;
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder::default()
+    }
 
+    fn new(port: usize, pool: PoolConfig, timeouts: Timeouts) -> anyhow::Result<Self> {
         let listener = TcpListener::bind(format!("[::]:{port}"))?;
+        // So that we notice when asked to shut down.
+        listener.set_nonblocking(true)?;
 
         Ok(Self {
-            config: config.into(),
             listener,
-            handlers: vec![],
+            hosts: VirtualHosts::default(),
+            pool,
+            timeouts,
+            shutdown: Arc::new(AtomicBool::new(false)),
+            reload: Arc::new(AtomicBool::new(false)),
+            access_log: None,
+            capture: None,
         })
     }
 
-    pub fn add_handler(&mut self, handler: Box<dyn Handler>) {
-        self.handlers.push(handler);
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.listener.local_addr()
     }
 
-    fn handle_request(&mut self, request: &str) -> Result<Response> {
-        let request = Request::from_str(request)?;
-        let resp = self
-            .handlers
-            .iter_mut()
+    /// Shut down gracefully on SIGTERM or SIGINT, and reload certificates and
+    /// reopen the access log and capture file on SIGHUP.
+    ///
+    /// A second SIGTERM or SIGINT terminates immediately, for the impatient.
+    pub fn handle_signals(&self) -> std::io::Result<()> {
+        use signal_hook::{
+            consts::{SIGHUP, SIGINT, SIGTERM},
+            flag,
+        };
+        flag::register(SIGHUP, self.reload.clone())?;
+        for signal in [SIGTERM, SIGINT] {
+            // Registered first, so it sees the flag as it was before this
+            // signal arrived.
+            flag::register_conditional_shutdown(signal, 1, self.shutdown.clone())?;
+            flag::register(signal, self.shutdown.clone())?;
+        }
+        Ok(())
+    }
+
+    /// Serve a capsule. Hosts are chosen by SNI for the handshake, and by
+    /// the request's host thereafter.
+    pub fn add_host(&mut self, host: VirtualHost) -> std::result::Result<(), VirtualHostError> {
+        self.hosts.add(host)
+    }
+
+    /// Accept connections until shut down, handing each to the worker pool.
+    ///
+    /// Once shutdown is requested we stop listening and give connections in
+    /// flight up to the grace period to finish.
+    pub fn run(self) -> anyhow::Result<()> {
+        if self.hosts.is_empty() {
+            anyhow::bail!("No hosts to serve");
+        }
+        let mut config = tls_config(self.hosts.resolver());
+        let service = Arc::new(Service {
+            hosts: self.hosts,
+            timeouts: self.timeouts,
+            access_log: self.access_log,
+            capture: self.capture,
+        });
+        let pool = ThreadPool::new(self.pool);
+        while !self.shutdown.load(Ordering::Relaxed) {
+            if self.reload.swap(false, Ordering::Relaxed) {
+                // Connections in flight keep the config they started with.
+                match service.hosts.reloaded_resolver() {
+                    Ok(resolver) => {
+                        config = tls_config(resolver);
+                        info!("Reloaded certificates");
+                    }
+                    Err(e) => error!("Keeping old certificates, failed to reload: {e}"),
+                }
+                if let Some(Err(e)) = service.access_log.as_ref().map(AccessLog::reopen) {
+                    error!("Failed to reopen the access log: {e}");
+                }
+                if let Some(Err(e)) = service.capture.as_ref().map(Recorder::reopen) {
+                    error!("Failed to reopen the capture file: {e}");
+                }
+            }
+            let (tcp_stream, peer) = match self.listener.accept() {
+                Ok(accepted) => accepted,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    std::thread::sleep(ACCEPT_POLL);
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            };
+            debug!("Accepted connection from {peer}");
+            tcp_stream.set_nonblocking(false)?;
+            let config = config.clone();
+            let service = service.clone();
+            pool.execute(move || service.serve(config, tcp_stream))?;
+        }
+
+        info!("Shutting down, waiting up to {:?}", self.timeouts.grace);
+        drop(self.listener);
+        if pool.shutdown(self.timeouts.grace) {
+            info!("All connections finished");
+        }
+        Ok(())
+    }
+}
+
+fn tls_config(resolver: SniResolver) -> Arc<ServerConfig> {
+    ServerConfig::builder()
+        .with_client_cert_verifier(Arc::new(AcceptAnyCert::new()))
+        .with_cert_resolver(Arc::new(resolver))
+        .into()
+}
+
+impl Service {
+    pub(crate) fn new(hosts: VirtualHosts, timeouts: Timeouts) -> Self {
+        Self {
+            hosts,
+            timeouts,
+            access_log: None,
+            capture: None,
+        }
+    }
+
+    /// For answering requests without a server around them.
+    pub(crate) fn offline(hosts: VirtualHosts) -> Self {
+        Self::new(hosts, Timeouts::default())
+    }
+
+    fn serve(&self, config: Arc<ServerConfig>, tcp_stream: TcpStream) {
+        let (started, timestamp) = (Instant::now(), time::OffsetDateTime::now_utc());
+        let peer_addr = tcp_stream.peer_addr().ok();
+        let mut served = Served::default();
+        self.converse(config, tcp_stream, peer_addr, &mut served);
+
+        if let (Some(capture), Some(request)) = (&self.capture, &served.request) {
+            capture.record(&Exchange {
+                time: rfc3339(timestamp),
+                request: String::from_utf8_lossy(request).into_owned(),
+                raw: std::str::from_utf8(request)
+                    .is_err()
+                    .then(|| to_hex(request)),
+                peer: peer_addr,
+                sni: served.sni.clone(),
+                client_certificate: served.certificate.clone(),
+                status: served.status,
+                meta: served.header.clone().map(|(_, meta)| meta),
+            });
+        }
+        if let Some(access_log) = &self.access_log {
+            access_log.log(&Entry {
+                timestamp,
+                peer: peer_addr,
+                sni: served.sni,
+                url: served
+                    .request
+                    .map(|raw| String::from_utf8_lossy(&raw).trim_end().to_owned()),
+                status: served.status,
+                bytes: served.bytes,
+                duration: started.elapsed(),
+                fingerprint: served.fingerprint,
+            });
+        }
+    }
+
+    /// Shake hands, read the request and answer it, noting in `served` how
+    /// far we got.
+    fn converse(
+        &self,
+        config: Arc<ServerConfig>,
+        tcp_stream: TcpStream,
+        peer_addr: Option<SocketAddr>,
+        served: &mut Served,
+    ) {
+        let peer = peer_addr.map_or_else(|| "unknown peer".into(), |addr| addr.to_string());
+        let mut conn = match ServerConnection::new(config) {
+            Ok(conn) => conn,
+            Err(e) => return warn!("Failed to set up TLS connection: {e:?}"),
+        };
+        let mut sock = TimeoutStream::new(tcp_stream);
+
+        sock.set_deadline(self.timeouts.handshake);
+        while conn.is_handshaking() {
+            match conn.complete_io(&mut sock) {
+                Ok(_) => {}
+                Err(e) if e.kind() == ErrorKind::TimedOut => {
+                    return warn!("TLS handshake with {peer} timed out");
+                }
+                Err(e) => return warn!("TLS handshake with {peer} failed: {e:?}"),
+            }
+        }
+
+        let client_certificate = conn
+            .peer_certificates()
+            .and_then(|chain| chain.first())
+            .map(|cert| ClientCertificate::new(cert.clone().into_owned()));
+
+        served.sni = conn.server_name().map(str::to_owned);
+        served.fingerprint = client_certificate
+            .as_ref()
+            .map(ClientCertificate::fingerprint_hex);
+        served.certificate = self
+            .capture
+            .as_ref()
+            .and(client_certificate.as_ref())
+            .map(|cert| to_hex(cert.der()));
+
+        sock.set_deadline(self.timeouts.read);
+        let raw = read_raw_request(&mut Stream::new(&mut conn, &mut sock));
+        served.request = raw.as_ref().ok().cloned();
+        let raw = raw
+            .map_err(Error::from)
+            .and_then(|raw| Ok(String::try_from(raw)?));
+        let Some(resp) = self.respond(raw, client_certificate, peer_addr) else {
+            return warn!("Reading request from {peer} timed out");
+        };
+
+        sock.set_idle_timeout(self.timeouts.write);
+        let mut out = Counting::new(Stream::new(&mut conn, &mut sock));
+        let sent = resp.send(&mut out);
+        (served.bytes, served.status, served.header) = (out.bytes(), out.status(), out.header());
+        let sent = sent.and_then(|_| {
+            conn.send_close_notify();
+            while conn.wants_write() {
+                conn.write_tls(&mut sock)?;
+            }
+            Ok(())
+        });
+        match sent {
+            Ok(_) => {}
+            Err(e) if e.kind() == ErrorKind::TimedOut => {
+                warn!("Sending response to {peer} timed out")
+            }
+            Err(e) => warn!("Failed to send response to {peer}: {e:?}"),
+        }
+    }
+
+    fn handle_request(
+        &self,
+        request: &str,
+        client_certificate: Option<ClientCertificate>,
+        peer_addr: Option<SocketAddr>,
+    ) -> Result<Response> {
+        let request = Request::from_str(request)?
+            .with_client_certificate(client_certificate)
+            .with_peer_addr(peer_addr);
+        let Some(host) = request
+            .url()
+            .host_str()
+            .and_then(|name| self.hosts.get(name))
+        else {
+            return Ok(Response::Err(ErrResponse::from_status(
+                Status::PermanentFailure(PermanentFailure::ProxyRequestRefused),
+            )));
+        };
+        let resp = host
+            .handlers()
+            .iter()
             .filter_map(|handler| handler.handle_request(&request))
             .next()
             .unwrap_or_else(|| {
@@ -82,44 +433,385 @@ impl Server {
         Ok(resp)
     }
 
-    fn handle<'a>(&mut self, mut stream: Stream<'a, ServerConnection, TcpStream>) {
-        let resp: Response = parse_raw_request(&mut stream)
-            .and_then(|raw| self.handle_request(raw.as_ref()))
-            .or_else(|e| -> std::result::Result<Response, ()> {
-                match e {
-                    Error::IO(_) => Ok(Response::Err(ErrResponse::from_status(
-                        Status::TemporaryFailure(TemporaryFailure::Generic),
-                    ))),
-                    Error::Utf8(e) => Ok(Response::Err(ErrResponse {
-                        status: Status::PermanentFailure(PermanentFailure::BadRequest),
-                        msg: Some(format!("{e:?}").replace("\n", " ").into()), // HACK
-                    })),
-                    Error::Request(ref e) => Ok(Response::Err(ErrResponse::from_status(e.into()))),
-                }
-            })
-            .unwrap(); // E is (), so this is safe
-        let _ = resp
-            .send(stream)
-            .inspect_err(|e| warn!("Failed to send response: {e:?}"));
-    }
-
-    pub fn run(&mut self) -> anyhow::Result<()> {
-        loop {
-            let (mut tcp_stream, _) = self.listener.accept()?;
-            let mut conn = ServerConnection::new(self.config.clone())?;
-            let tls_stream = Stream::new(&mut conn, &mut tcp_stream);
-            self.handle(tls_stream);
-        }
+    /// Work out the response to what the client sent, or `None` if it took
+    /// too long to ask.
+    pub(crate) fn respond(
+        &self,
+        raw: Result<String>,
+        client_certificate: Option<ClientCertificate>,
+        peer_addr: Option<SocketAddr>,
+    ) -> Option<Response> {
+        let resp = match raw
+            .and_then(|raw| self.handle_request(raw.as_ref(), client_certificate, peer_addr))
+        {
+            Ok(resp) => resp,
+            Err(Error::IO(ref e)) if e.kind() == ErrorKind::TimedOut => return None,
+            Err(Error::IO(_)) => Response::Err(ErrResponse::from_status(Status::TemporaryFailure(
+                TemporaryFailure::Generic,
+            ))),
+            Err(Error::Utf8(e)) => Response::Err(ErrResponse {
+                status: Status::PermanentFailure(PermanentFailure::BadRequest),
+                msg: Some(format!("{e:?}").replace("\n", " ").into()), // HACK
+            }),
+            Err(Error::Request(ref e)) => Response::Err(ErrResponse::from_status(e.into())),
+        };
+        Some(resp)
     }
 }
 
-impl TryFrom<&cli::Serve> for Server {
-    type Error = anyhow::Error;
-    fn try_from(value: &cli::Serve) -> std::result::Result<Self, Self::Error> {
-        Self::new(
-            CertificateDer::from_pem_file(&value.certificate).expect("certificate"),
-            PrivateKeyDer::from_pem_file(&value.private_key).expect("certificate"),
-            value.port,
+#[cfg(test)]
+mod test_timeouts {
+    use super::*;
+    use crate::{
+        access_log::{LogFormat, LogTarget},
+        testing::{Named, client_config, host},
+    };
+    use anyhow::Result;
+    use rustls::ClientConnection;
+    use std::{io::Write, time::Instant};
+
+    fn service() -> Result<Service> {
+        crate::testing::service(
+            vec![host(&["localhost"], Named("localhost"))?],
+            Duration::from_millis(100),
         )
     }
+
+    /// Serve a connection from `server`, however far it gets.
+    fn serve(service: &Service, server: TcpStream) {
+        service.serve(tls_config(service.hosts.resolver()), server);
+    }
+
+    #[test]
+    fn a_client_which_stalls_during_the_handshake_is_dropped() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let mut client = TcpStream::connect(listener.local_addr()?)?;
+        let (server, _) = listener.accept()?;
+
+        // Half a TLS record header, then nothing.
+        client.write_all(&[0x16, 0x03])?;
+
+        let start = Instant::now();
+        serve(&service()?, server);
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        // The server has hung up without a word.
+        client.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let read = client.read(&mut [0; 1]);
+        assert!(!matches!(read, Ok(n) if n > 0));
+        Ok(())
+    }
+
+    #[test]
+    fn a_client_which_sends_nothing_is_dropped() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let _client = TcpStream::connect(listener.local_addr()?)?;
+        let (server, _) = listener.accept()?;
+
+        let start = Instant::now();
+        serve(&service()?, server);
+        assert!(start.elapsed() < Duration::from_secs(5));
+        Ok(())
+    }
+
+    /// The access log line written for a client which gets as far as
+    /// `client` lets it.
+    fn logged(client: impl FnOnce(TcpStream) -> Result<()> + Send + 'static) -> Result<String> {
+        let dir = tempfile::TempDir::new()?;
+        let path = dir.path().join("access.log");
+        let mut service = service()?;
+        service.access_log = Some(AccessLog::open(
+            LogTarget::File(path.clone()),
+            LogFormat::Common,
+        )?);
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let client = std::thread::spawn({
+            let addr = listener.local_addr()?;
+            move || client(TcpStream::connect(addr)?)
+        });
+        let (server, _) = listener.accept()?;
+
+        serve(&service, server);
+
+        client.join().expect("client thread")?;
+        Ok(std::fs::read_to_string(path)?)
+    }
+
+    #[test]
+    fn dropped_clients_are_logged() -> Result<()> {
+        let stalled = logged(|mut client| {
+            client.write_all(&[0x16, 0x03])?;
+            std::thread::sleep(Duration::from_millis(200));
+            Ok(())
+        })?;
+        assert!(stalled.starts_with("127.0.0.1 - - ["), "{stalled}");
+        assert!(stalled.contains("] \"-\" - 0 \"-\" "), "{stalled}");
+
+        let silent = logged(|mut client| {
+            let mut conn = ClientConnection::new(client_config(None), "localhost".try_into()?)?;
+            while conn.is_handshaking() {
+                conn.complete_io(&mut client)?;
+            }
+            std::thread::sleep(Duration::from_millis(200));
+            Ok(())
+        })?;
+        assert!(silent.contains("] \"-\" - 0 \"localhost\" "), "{silent}");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test_shutdown {
+    use super::*;
+    use crate::testing::{Named, host};
+    use anyhow::Result;
+    use std::time::Instant;
+
+    fn server(grace: Duration) -> Result<Server> {
+        Server::builder()
+            .port(0)
+            .pool(PoolConfig {
+                workers: 1.try_into()?,
+                queue_depth: 1,
+            })
+            .timeouts(Timeouts {
+                grace,
+                ..Timeouts::default()
+            })
+            .host(host(&["localhost"], Named("localhost"))?)
+            .build()
+    }
+
+    #[test]
+    fn run_returns_once_shutdown_is_requested() -> Result<()> {
+        let server = server(Duration::from_secs(5))?;
+        let shutdown = server.shutdown.clone();
+        let running = std::thread::spawn(move || server.run());
+
+        shutdown.store(true, Ordering::Relaxed);
+
+        let start = Instant::now();
+        running.join().expect("server thread")?;
+        assert!(start.elapsed() < Duration::from_secs(5));
+        Ok(())
+    }
+
+    #[test]
+    fn shutdown_stops_accepting_connections() -> Result<()> {
+        let server = server(Duration::from_secs(5))?;
+        let port = server.local_addr()?.port();
+        let shutdown = server.shutdown.clone();
+        let running = std::thread::spawn(move || server.run());
+
+        shutdown.store(true, Ordering::Relaxed);
+        running.join().expect("server thread")?;
+
+        assert!(TcpStream::connect(("localhost", port)).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn connections_in_flight_are_abandoned_after_the_grace_period() -> Result<()> {
+        let server = server(Duration::from_millis(100))?;
+        let port = server.local_addr()?.port();
+        let shutdown = server.shutdown.clone();
+        let running = std::thread::spawn(move || server.run());
+
+        // Connect but never handshake, so the worker waits on us.
+        let _client = TcpStream::connect(("localhost", port))?;
+        std::thread::sleep(ACCEPT_POLL * 2);
+        shutdown.store(true, Ordering::Relaxed);
+
+        let start = Instant::now();
+        running.join().expect("server thread")?;
+        assert!(start.elapsed() < Duration::from_secs(5));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test_virtual_hosts {
+    use super::*;
+    use crate::testing::{Named, host, sent};
+    use anyhow::Result;
+    use rstest::rstest;
+
+    fn service(hosts: Vec<VirtualHost>) -> Result<Service> {
+        crate::testing::service(hosts, Duration::from_secs(1))
+    }
+
+    #[rstest]
+    #[case("gemini://foo.example.com/", "20 text/plain\r\nfoo")]
+    #[case("gemini://BAR.example.com/x", "20 text/plain\r\nbar")]
+    #[case("gemini://baz.example.com/", "20 text/plain\r\nbar")]
+    fn requests_are_routed_by_host(#[case] url: &str, #[case] expected: &str) -> Result<()> {
+        let service = service(vec![
+            host(&["foo.example.com"], Named("foo"))?,
+            host(&["bar.example.com", "baz.example.com"], Named("bar"))?,
+        ])?;
+        assert_eq!(
+            sent(service.handle_request(&format!("{url}\r\n"), None, None)?)?,
+            expected
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn requests_for_unknown_hosts_are_refused() -> Result<()> {
+        let service = service(vec![host(&["foo.example.com"], Named("foo"))?])?;
+        assert_eq!(
+            sent(service.handle_request("gemini://elsewhere.example.com/\r\n", None, None)?)?,
+            "53 PermanentFailure(ProxyRequestRefused)\r\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn requests_for_unknown_hosts_go_to_a_wildcard_host() -> Result<()> {
+        let service = service(vec![
+            host(&["foo.example.com"], Named("foo"))?,
+            host(&[], Named("anything"))?,
+        ])?;
+        assert_eq!(
+            sent(service.handle_request("gemini://elsewhere.example.com/\r\n", None, None)?)?,
+            "20 text/plain\r\nanything"
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test_client_certificates {
+    use super::*;
+    use crate::{
+        access_log::{LogFormat, LogTarget},
+        testing::{Named, client_config, fetch, host, self_signed, timeouts},
+    };
+    use anyhow::Result;
+
+    fn whoami(
+        access_log: Option<AccessLog>,
+        request: impl FnOnce(SocketAddr) -> Result<String>,
+    ) -> Result<String> {
+        let mut server = Server::new(
+            0,
+            PoolConfig {
+                workers: 1.try_into()?,
+                queue_depth: 1,
+            },
+            timeouts(Duration::from_secs(5)),
+        )?;
+        server.add_host(host(&["localhost"], Named("whoami"))?)?;
+        server.access_log = access_log;
+        let addr = SocketAddr::from(([127, 0, 0, 1], server.local_addr()?.port()));
+        let shutdown = server.shutdown.clone();
+        let running = std::thread::spawn(move || server.run());
+
+        let response = request(addr);
+
+        shutdown.store(true, Ordering::Relaxed);
+        running.join().expect("server thread")?;
+        response
+    }
+
+    #[test]
+    fn handlers_see_the_fingerprint_of_a_client_certificate() -> Result<()> {
+        let (cert, key) = self_signed(&["me"]);
+        let expected = ClientCertificate::new(cert.clone()).fingerprint_hex();
+        let config = client_config(Some((cert, key)));
+
+        let response = whoami(None, |addr| fetch(addr, "gemini://localhost/", config))?;
+
+        assert_eq!(response, format!("20 text/plain\r\nwhoami cert={expected}"));
+        Ok(())
+    }
+
+    #[test]
+    fn client_certificates_are_not_required_to_connect() -> Result<()> {
+        let response = whoami(None, |addr| {
+            fetch(addr, "gemini://localhost/", client_config(None))
+        })?;
+
+        assert_eq!(response, "20 text/plain\r\nwhoami");
+        Ok(())
+    }
+
+    #[test]
+    fn responses_are_logged() -> Result<()> {
+        let dir = tempfile::TempDir::new()?;
+        let path = dir.path().join("access.log");
+        let access_log = AccessLog::open(LogTarget::File(path.clone()), LogFormat::Json)?;
+        let (cert, key) = self_signed(&["me"]);
+        let fingerprint = ClientCertificate::new(cert.clone()).fingerprint_hex();
+        let config = client_config(Some((cert, key)));
+
+        let response = whoami(Some(access_log), |addr| {
+            fetch(addr, "gemini://localhost/?hi", config)
+        })?;
+
+        let line: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+        assert_eq!(line["url"], "gemini://localhost/?hi");
+        assert_eq!(line["sni"], "localhost");
+        assert_eq!(line["status"], 20);
+        assert_eq!(line["bytes"], response.len());
+        assert_eq!(line["fingerprint"], fingerprint);
+        assert!(
+            line["peer"]
+                .as_str()
+                .is_some_and(|peer| peer.starts_with("127.0.0.1:"))
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test_capture {
+    use super::*;
+    use crate::{
+        capture::{Exchange, Replayer},
+        testing::{Named, client_config, host, service},
+    };
+    use anyhow::Result;
+    use rstest::rstest;
+    use rustls::{ClientConnection, StreamOwned};
+    use std::io::Write;
+
+    #[rstest]
+    #[case::not_utf8(b"gemini://localhost/\xff\r\n")]
+    #[case::not_a_url(b"hello\r\n")]
+    fn malformed_requests_are_captured(#[case] request: &'static [u8]) -> Result<()> {
+        let dir = tempfile::TempDir::new()?;
+        let path = dir.path().join("capture.jsonl");
+        let mut service = service(
+            vec![host(&["localhost"], Named("localhost"))?],
+            Duration::from_secs(5),
+        )?;
+        service.capture = Some(Recorder::open(&path)?);
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let client = std::thread::spawn(move || -> Result<Vec<u8>> {
+            let conn = ClientConnection::new(client_config(None), "localhost".try_into()?)?;
+            let mut stream = StreamOwned::new(conn, TcpStream::connect(addr)?);
+            stream.write_all(request)?;
+            stream.flush()?;
+            stream.sock.shutdown(std::net::Shutdown::Write)?;
+            let mut response = Vec::new();
+            stream.read_to_end(&mut response)?;
+            Ok(response)
+        });
+        let (server, _) = listener.accept()?;
+
+        service.serve(tls_config(service.hosts.resolver()), server);
+
+        let response = client.join().expect("client thread")?;
+        assert!(response.starts_with(b"59 "), "{response:?}");
+        let capture = std::fs::read_to_string(path)?;
+        let exchange: Exchange = serde_json::from_str(&capture)?;
+        assert_eq!(exchange.request_bytes(), request);
+        assert_eq!(exchange.status, Some(59));
+        let replayed = VirtualHost::without_certificate(["localhost"]);
+        let report = Replayer::new(vec![replayed])?.replay_all(capture.as_bytes())?;
+        assert_eq!(report.differences, []);
+        Ok(())
+    }
 }
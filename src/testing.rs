@@ -0,0 +1,140 @@
+//! Helpers shared between tests.
+
+use std::{
+    io::{Read, Write},
+    net::{SocketAddr, TcpStream},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use rustls::{
+    ClientConfig, ClientConnection, StreamOwned,
+    pki_types::{CertificateDer, PrivateKeyDer, ServerName},
+};
+
+use crate::{
+    handler::Handler,
+    net::AcceptAnyCert,
+    request::Request,
+    response::{Response, SuccessResponse},
+    server::Service,
+    status::Success,
+    timeout::Timeouts,
+    vhost::{VirtualHost, VirtualHosts},
+};
+
+/// A file from the `testdata` directory.
+pub fn testdata(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("testdata")
+        .join(name)
+}
+
+/// Ask `handler` for `url`, returning everything it sends, or `None` if it
+/// declines.
+pub fn get(handler: &dyn Handler, url: &str) -> anyhow::Result<Option<String>> {
+    handler
+        .handle_request(&format!("{url}\r\n").parse()?)
+        .map(sent)
+        .transpose()
+}
+
+/// Everything `response` sends, header and all.
+pub fn sent(response: Response) -> anyhow::Result<String> {
+    let mut buffer = Vec::new();
+    response.send(&mut buffer)?;
+    Ok(String::try_from(buffer)?)
+}
+
+/// A throwaway self-signed certificate for `names`, and its key.
+pub fn self_signed(names: &[&str]) -> (CertificateDer<'static>, PrivateKeyDer<'static>) {
+    let names = names
+        .iter()
+        .map(|name| name.to_string())
+        .collect::<Vec<_>>();
+    let cert = rcgen::generate_simple_self_signed(names).expect("generate certificate");
+    let key = PrivateKeyDer::try_from(cert.signing_key.serialize_der()).expect("private key");
+    (cert.cert.der().clone(), key)
+}
+
+/// A client which trusts anything, optionally identifying itself.
+pub fn client_config(
+    identity: Option<(CertificateDer<'static>, PrivateKeyDer<'static>)>,
+) -> Arc<ClientConfig> {
+    let builder = ClientConfig::builder()
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(AcceptAnyCert::new()));
+    match identity {
+        Some((cert, key)) => builder
+            .with_client_auth_cert(vec![cert], key)
+            .expect("client identity"),
+        None => builder.with_no_client_auth(),
+    }
+    .into()
+}
+
+/// Request `url` from the server at `addr`, returning the whole response.
+pub fn fetch(addr: SocketAddr, url: &str, config: Arc<ClientConfig>) -> anyhow::Result<String> {
+    let host = url::Url::parse(url)?
+        .host_str()
+        .map(str::to_owned)
+        .unwrap_or_default();
+    let conn = ClientConnection::new(config, ServerName::try_from(host)?)?;
+    let mut stream = StreamOwned::new(conn, TcpStream::connect(addr)?);
+    write!(stream, "{url}\r\n")?;
+    let mut buf = Vec::new();
+    stream.read_to_end(&mut buf)?;
+    Ok(String::from_utf8(buf)?)
+}
+
+/// Answers `20 text/plain` with its name, then any of the route params
+/// `name`, `id` and `rest` it was given, then the client's fingerprint.
+pub struct Named(pub &'static str);
+
+impl Handler for Named {
+    fn handle_request(&self, request: &Request) -> Option<Response> {
+        let mut body = self.0.to_owned();
+        for param in ["name", "id", "rest"] {
+            if let Some(value) = request.param(param) {
+                body.push_str(&format!(" {param}={value}"));
+            }
+        }
+        if let Some(certificate) = request.client_certificate() {
+            body.push_str(&format!(" cert={}", certificate.fingerprint_hex()));
+        }
+        Some(Response::Fixed(SuccessResponse {
+            status: Success::Generic,
+            mime: "text/plain".into(),
+            body: body.into(),
+        }))
+    }
+}
+
+/// `timeout` for every phase of a connection.
+pub fn timeouts(timeout: Duration) -> Timeouts {
+    Timeouts {
+        handshake: timeout,
+        read: timeout,
+        write: timeout,
+        grace: timeout,
+    }
+}
+
+/// A host answering to `names`, or anything if empty, with `handler` and a
+/// throwaway certificate for `localhost`.
+pub fn host(names: &[&str], handler: impl Handler + 'static) -> anyhow::Result<VirtualHost> {
+    let (cert, key) = self_signed(&["localhost"]);
+    let mut host = VirtualHost::new(names.iter().copied(), vec![cert], key)?;
+    host.add_handler(Box::new(handler));
+    Ok(host)
+}
+
+/// What a worker would serve `hosts` with, allowing `timeout` for each phase.
+pub(crate) fn service(hosts: Vec<VirtualHost>, timeout: Duration) -> anyhow::Result<Service> {
+    let mut virtual_hosts = VirtualHosts::default();
+    for host in hosts {
+        virtual_hosts.add(host)?;
+    }
+    Ok(Service::new(virtual_hosts, timeouts(timeout)))
+}
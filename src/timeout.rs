@@ -0,0 +1,178 @@
+use std::{
+    io::{ErrorKind, Read, Write},
+    net::TcpStream,
+    time::{Duration, Instant},
+};
+
+/// How long a client may take over each phase of a connection.
+#[derive(Debug, Clone, Copy)]
+pub struct Timeouts {
+    /// Total time allowed for the TLS handshake.
+    pub handshake: Duration,
+    /// Total time allowed for the client to send its request line.
+    pub read: Duration,
+    /// Time allowed for the client to accept each chunk of the response.
+    ///
+    /// This is an idle timeout rather than a deadline, so large files may
+    /// still be sent to slow clients.
+    pub write: Duration,
+    /// Time connections in flight get to finish once shutdown begins.
+    pub grace: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            handshake: Duration::from_secs(10),
+            read: Duration::from_secs(10),
+            write: Duration::from_secs(30),
+            grace: Duration::from_secs(30),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Limit {
+    None,
+    Deadline(Instant),
+    Idle(Duration),
+}
+
+/// A socket whose reads and writes fail once a limit has passed.
+///
+/// Timeouts are always reported as [`ErrorKind::TimedOut`], whatever the
+/// platform would otherwise say.
+#[derive(Debug)]
+pub struct TimeoutStream {
+    inner: TcpStream,
+    limit: Limit,
+}
+
+impl TimeoutStream {
+    pub fn new(inner: TcpStream) -> Self {
+        Self {
+            inner,
+            limit: Limit::None,
+        }
+    }
+
+    /// Fail every operation once `timeout` has elapsed from now.
+    pub fn set_deadline(&mut self, timeout: Duration) {
+        self.limit = Limit::Deadline(Instant::now() + timeout);
+    }
+
+    /// Fail any single operation which makes no progress for `timeout`.
+    pub fn set_idle_timeout(&mut self, timeout: Duration) {
+        self.limit = Limit::Idle(timeout);
+    }
+
+    /// The time the next operation may block for. A limit of zero has
+    /// always passed, as the socket would refuse a zero timeout.
+    fn remaining(&self) -> std::io::Result<Option<Duration>> {
+        let remaining = match self.limit {
+            Limit::None => return Ok(None),
+            Limit::Idle(timeout) => Some(timeout),
+            Limit::Deadline(deadline) => deadline.checked_duration_since(Instant::now()),
+        };
+        remaining
+            .filter(|remaining| !remaining.is_zero())
+            .map(Some)
+            .ok_or_else(|| ErrorKind::TimedOut.into())
+    }
+}
+
+fn normalise<T>(result: std::io::Result<T>) -> std::io::Result<T> {
+    result.map_err(|e| match e.kind() {
+        ErrorKind::WouldBlock => ErrorKind::TimedOut.into(),
+        _ => e,
+    })
+}
+
+impl Read for TimeoutStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let timeout = self.remaining()?;
+        self.inner.set_read_timeout(timeout)?;
+        normalise(self.inner.read(buf))
+    }
+}
+
+impl Write for TimeoutStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let timeout = self.remaining()?;
+        self.inner.set_write_timeout(timeout)?;
+        normalise(self.inner.write(buf))
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use anyhow::Result;
+    use std::net::TcpListener;
+
+    fn pair() -> Result<(TcpStream, TcpStream)> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let client = TcpStream::connect(listener.local_addr()?)?;
+        let (server, _) = listener.accept()?;
+        Ok((client, server))
+    }
+
+    #[test]
+    fn a_read_from_a_stalled_client_times_out() -> Result<()> {
+        let (_client, server) = pair()?;
+        let mut stream = TimeoutStream::new(server);
+        stream.set_deadline(Duration::from_millis(50));
+
+        let start = Instant::now();
+        let err = stream.read(&mut [0; 8]).expect_err("timed out");
+
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        assert!(start.elapsed() < Duration::from_secs(5));
+        Ok(())
+    }
+
+    #[test]
+    fn a_deadline_bounds_the_total_time_not_each_read() -> Result<()> {
+        let (mut client, server) = pair()?;
+        let mut stream = TimeoutStream::new(server);
+        stream.set_deadline(Duration::from_millis(100));
+
+        client.write_all(b"a")?;
+        assert_eq!(stream.read(&mut [0; 8])?, 1);
+        std::thread::sleep(Duration::from_millis(150));
+
+        let err = stream.read(&mut [0; 8]).expect_err("timed out");
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        Ok(())
+    }
+
+    #[test]
+    fn a_zero_idle_timeout_times_out() -> Result<()> {
+        let (_client, server) = pair()?;
+        let mut stream = TimeoutStream::new(server);
+        stream.set_idle_timeout(Duration::ZERO);
+
+        let err = stream.write(b"a").expect_err("timed out");
+
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        Ok(())
+    }
+
+    #[test]
+    fn an_idle_timeout_resets_on_progress() -> Result<()> {
+        let (mut client, server) = pair()?;
+        let mut stream = TimeoutStream::new(server);
+        stream.set_idle_timeout(Duration::from_millis(200));
+
+        for _ in 0..3 {
+            std::thread::sleep(Duration::from_millis(100));
+            client.write_all(b"a")?;
+            assert_eq!(stream.read(&mut [0; 8])?, 1);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,424 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use rustls::{
+    pki_types::{CertificateDer, PrivateKeyDer},
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+};
+
+use crate::{
+    handler::Handler,
+    identity::{Identity, IdentityError},
+};
+
+/// A capsule: the names it answers to, its certificate and its handlers.
+pub struct VirtualHost {
+    /// Lowercase hostnames this host serves. Empty means any host.
+    names: Vec<String>,
+    /// None for hosts which are never offered in a handshake.
+    key: Option<Arc<CertifiedKey>>,
+    /// Where the key came from, so that it can be reloaded.
+    files: Option<KeyFiles>,
+    handlers: Vec<Box<dyn Handler>>,
+}
+
+#[derive(Debug, Clone)]
+struct KeyFiles {
+    certificate: PathBuf,
+    private_key: PathBuf,
+}
+
+impl KeyFiles {
+    fn load(&self) -> Result<CertifiedKey, IdentityError> {
+        let Identity { chain, key } =
+            Identity::from_pem_files(&self.certificate, &self.private_key)?;
+        certified_key(chain, key).map_err(|source| IdentityError::Mismatch {
+            certificate: self.certificate.clone(),
+            private_key: self.private_key.clone(),
+            source,
+        })
+    }
+}
+
+fn certified_key(
+    chain: Vec<CertificateDer<'static>>,
+    key: PrivateKeyDer<'static>,
+) -> Result<CertifiedKey, rustls::Error> {
+    CertifiedKey::from_der(chain, key, &rustls::crypto::aws_lc_rs::default_provider())
+}
+
+impl std::fmt::Debug for VirtualHost {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("VirtualHost")
+            .field("names", &self.names)
+            .field("handlers", &self.handlers.len())
+            .finish()
+    }
+}
+
+impl VirtualHost {
+    /// A host serving `names` (or anything, if empty) with the given chain.
+    pub fn new(
+        names: impl IntoIterator<Item = impl Into<String>>,
+        chain: Vec<CertificateDer<'static>>,
+        key: PrivateKeyDer<'static>,
+    ) -> Result<Self, rustls::Error> {
+        Ok(Self::with_key(
+            names,
+            Some(certified_key(chain, key)?),
+            None,
+        ))
+    }
+
+    /// A host with no certificate, for answering requests which don't
+    /// arrive over TLS, as when replaying a capture.
+    pub fn without_certificate(names: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        Self::with_key(names, None, None)
+    }
+
+    /// A host whose certificate may be reloaded from the files it came from.
+    pub fn from_pem_files(
+        names: impl IntoIterator<Item = impl Into<String>>,
+        certificate: &Path,
+        private_key: &Path,
+    ) -> Result<Self, IdentityError> {
+        let files = KeyFiles {
+            certificate: certificate.into(),
+            private_key: private_key.into(),
+        };
+        Ok(Self::with_key(names, Some(files.load()?), Some(files)))
+    }
+
+    fn with_key(
+        names: impl IntoIterator<Item = impl Into<String>>,
+        key: Option<CertifiedKey>,
+        files: Option<KeyFiles>,
+    ) -> Self {
+        Self {
+            names: names
+                .into_iter()
+                .map(|name| name.into().to_ascii_lowercase())
+                .collect(),
+            key: key.map(Arc::new),
+            files,
+            handlers: vec![],
+        }
+    }
+
+    pub fn add_handler(&mut self, handler: Box<dyn Handler>) {
+        self.handlers.push(handler);
+    }
+
+    pub fn handlers(&self) -> &[Box<dyn Handler>] {
+        &self.handlers
+    }
+
+    fn is_wildcard(&self) -> bool {
+        self.names.is_empty()
+    }
+}
+
+/// The configured hosts, looked up by name.
+#[derive(Debug, Default)]
+pub struct VirtualHosts {
+    hosts: Vec<VirtualHost>,
+    by_name: HashMap<String, usize>,
+    wildcard: Option<usize>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum VirtualHostError {
+    #[error("Host `{0}` is configured more than once")]
+    DuplicateName(String),
+    #[error("Only one host may answer to any name")]
+    DuplicateWildcard,
+}
+
+impl VirtualHosts {
+    pub fn add(&mut self, host: VirtualHost) -> Result<(), VirtualHostError> {
+        let index = self.hosts.len();
+        if host.is_wildcard() {
+            if self.wildcard.is_some() {
+                return Err(VirtualHostError::DuplicateWildcard);
+            }
+            self.wildcard = Some(index);
+        }
+        for name in &host.names {
+            if self.by_name.contains_key(name) {
+                return Err(VirtualHostError::DuplicateName(name.clone()));
+            }
+        }
+        for name in &host.names {
+            self.by_name.insert(name.clone(), index);
+        }
+        self.hosts.push(host);
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hosts.is_empty()
+    }
+
+    /// The host serving `name`, falling back to the wildcard host if any.
+    pub fn get(&self, name: &str) -> Option<&VirtualHost> {
+        self.by_name
+            .get(&name.to_ascii_lowercase())
+            .or(self.wildcard.as_ref())
+            .map(|&index| &self.hosts[index])
+    }
+
+    /// A certificate resolver keyed by SNI.
+    pub fn resolver(&self) -> SniResolver {
+        self.resolver_with(self.hosts.iter().map(|host| host.key.clone()).collect())
+    }
+
+    /// A certificate resolver with every certificate loaded afresh from disk.
+    ///
+    /// Fails if any host's files are no longer valid, so that a botched
+    /// rotation never leaves a host without a certificate.
+    pub fn reloaded_resolver(&self) -> Result<SniResolver, IdentityError> {
+        let keys = self
+            .hosts
+            .iter()
+            .map(|host| match &host.files {
+                Some(files) => files.load().map(|key| Some(Arc::new(key))),
+                None => Ok(host.key.clone()),
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(self.resolver_with(keys))
+    }
+
+    /// `keys` are in the same order as `self.hosts`. When we don't recognise
+    /// the name asked for (or weren't given one) we present the wildcard
+    /// host's certificate, or failing that the first we have.
+    fn resolver_with(&self, keys: Vec<Option<Arc<CertifiedKey>>>) -> SniResolver {
+        SniResolver {
+            by_name: self
+                .by_name
+                .iter()
+                .filter_map(|(name, &index)| Some((name.clone(), keys[index].clone()?)))
+                .collect(),
+            fallback: self
+                .wildcard
+                .and_then(|index| keys[index].clone())
+                .or_else(|| keys.iter().flatten().next().cloned()),
+        }
+    }
+}
+
+/// Picks a certificate by SNI, falling back to the default host's.
+///
+/// Unknown names still get a certificate, so that the request can be refused
+/// properly once it arrives.
+#[derive(Debug)]
+pub struct SniResolver {
+    by_name: HashMap<String, Arc<CertifiedKey>>,
+    fallback: Option<Arc<CertifiedKey>>,
+}
+
+impl SniResolver {
+    fn get(&self, server_name: Option<&str>) -> Option<Arc<CertifiedKey>> {
+        server_name
+            .and_then(|name| self.by_name.get(&name.to_ascii_lowercase()))
+            .or(self.fallback.as_ref())
+            .cloned()
+    }
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        self.get(client_hello.server_name())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        gencert::SelfSigned,
+        testing::{self_signed, testdata},
+    };
+    use tempfile::TempDir;
+
+    fn host(names: &[&str]) -> VirtualHost {
+        let (cert, key) = self_signed(if names.is_empty() { &["x"] } else { names });
+        VirtualHost::new(names.iter().copied(), vec![cert], key).unwrap()
+    }
+
+    #[test]
+    fn hosts_are_found_by_name_case_insensitively() {
+        let mut hosts = VirtualHosts::default();
+        hosts.add(host(&["foo.example.com"])).unwrap();
+        hosts
+            .add(host(&["bar.example.com", "Baz.example.com"]))
+            .unwrap();
+
+        assert_eq!(
+            hosts.get("FOO.example.com").unwrap().names,
+            ["foo.example.com"]
+        );
+        assert_eq!(
+            hosts.get("baz.example.com").unwrap().names,
+            ["bar.example.com", "baz.example.com"]
+        );
+        assert!(hosts.get("quux.example.com").is_none());
+    }
+
+    #[test]
+    fn unknown_names_go_to_the_wildcard_host() {
+        let mut hosts = VirtualHosts::default();
+        hosts.add(host(&["foo.example.com"])).unwrap();
+        hosts.add(host(&[])).unwrap();
+
+        assert!(hosts.get("quux.example.com").unwrap().is_wildcard());
+        assert!(!hosts.get("foo.example.com").unwrap().is_wildcard());
+    }
+
+    #[test]
+    fn a_name_may_only_be_served_once() {
+        let mut hosts = VirtualHosts::default();
+        hosts.add(host(&["foo.example.com"])).unwrap();
+
+        assert!(matches!(
+            hosts.add(host(&["FOO.example.com"])),
+            Err(VirtualHostError::DuplicateName(_))
+        ));
+        hosts.add(host(&[])).unwrap();
+        assert!(matches!(
+            hosts.add(host(&[])),
+            Err(VirtualHostError::DuplicateWildcard)
+        ));
+    }
+
+    #[test]
+    fn a_mismatched_key_is_rejected() {
+        let (cert, _) = self_signed(&["foo.example.com"]);
+        let (_, key) = self_signed(&["foo.example.com"]);
+        assert!(VirtualHost::new(["foo.example.com"], vec![cert], key).is_err());
+    }
+
+    #[rstest::rstest]
+    #[case::rsa("rsa.crt", "rsa-pkcs1.key")]
+    #[case::ec("ec.crt", "ec-pkcs8.key")]
+    #[case::chain("chain.crt", "ec-sec1.key")]
+    fn hosts_are_loaded_from_pem_files(#[case] certificate: &str, #[case] private_key: &str) {
+        let host = VirtualHost::from_pem_files(
+            ["localhost"],
+            &testdata(certificate),
+            &testdata(private_key),
+        );
+        assert!(host.is_ok());
+    }
+
+    #[test]
+    fn a_mismatched_key_file_names_both_files() {
+        let err = VirtualHost::from_pem_files(
+            ["localhost"],
+            &testdata("ec.crt"),
+            &testdata("rsa-pkcs1.key"),
+        )
+        .unwrap_err();
+        assert!(matches!(err, IdentityError::Mismatch { .. }));
+        assert!(err.to_string().contains("ec.crt"));
+        assert!(err.to_string().contains("rsa-pkcs1.key"));
+    }
+
+    #[test]
+    fn unknown_names_get_the_wildcard_hosts_certificate() {
+        let mut hosts = VirtualHosts::default();
+        hosts.add(host(&["foo.example.com"])).unwrap();
+        let foo = hosts.hosts[0].key.clone().unwrap();
+        hosts.add(host(&[])).unwrap();
+        let wildcard = hosts.hosts[1].key.clone().unwrap();
+        let resolver = hosts.resolver();
+
+        assert!(Arc::ptr_eq(
+            &resolver.get(Some("foo.example.com")).unwrap(),
+            &foo
+        ));
+        assert!(Arc::ptr_eq(
+            &resolver.get(Some("bar.example.com")).unwrap(),
+            &wildcard
+        ));
+        assert!(Arc::ptr_eq(&resolver.get(None).unwrap(), &wildcard));
+    }
+
+    #[test]
+    fn hosts_without_certificates_are_never_offered() {
+        let mut hosts = VirtualHosts::default();
+        hosts
+            .add(VirtualHost::without_certificate(["foo.example.com"]))
+            .unwrap();
+        hosts.add(host(&["bar.example.com"])).unwrap();
+        let bar = hosts.hosts[1].key.clone().unwrap();
+        let resolver = hosts.resolver();
+
+        assert!(hosts.get("foo.example.com").is_some());
+        assert!(Arc::ptr_eq(
+            &resolver.get(Some("foo.example.com")).unwrap(),
+            &bar
+        ));
+    }
+
+    fn write_identity(dir: &Path) -> CertificateDer<'static> {
+        let generated = SelfSigned::generate(&["localhost".into()], 1).unwrap();
+        generated
+            .write(&dir.join("cert.pem"), &dir.join("key.pem"), true)
+            .unwrap();
+        Identity::from_pem_files(&dir.join("cert.pem"), &dir.join("key.pem"))
+            .unwrap()
+            .chain
+            .remove(0)
+    }
+
+    fn reloadable_hosts(dir: &Path) -> VirtualHosts {
+        let mut hosts = VirtualHosts::default();
+        hosts
+            .add(
+                VirtualHost::from_pem_files(
+                    ["localhost"],
+                    &dir.join("cert.pem"),
+                    &dir.join("key.pem"),
+                )
+                .unwrap(),
+            )
+            .unwrap();
+        hosts
+    }
+
+    #[test]
+    fn reloading_picks_up_new_certificates() {
+        let dir = TempDir::new().unwrap();
+        let old = write_identity(dir.path());
+        let hosts = reloadable_hosts(dir.path());
+        let new = write_identity(dir.path());
+
+        assert_eq!(
+            hosts.resolver().get(Some("localhost")).unwrap().cert[0],
+            old
+        );
+        assert_eq!(
+            hosts
+                .reloaded_resolver()
+                .unwrap()
+                .get(Some("localhost"))
+                .unwrap()
+                .cert[0],
+            new
+        );
+    }
+
+    #[test]
+    fn reloading_fails_if_the_new_files_are_invalid() {
+        let dir = TempDir::new().unwrap();
+        write_identity(dir.path());
+        let hosts = reloadable_hosts(dir.path());
+        std::fs::write(dir.path().join("key.pem"), "garbage").unwrap();
+
+        assert!(hosts.reloaded_resolver().is_err());
+    }
+}